@@ -0,0 +1,485 @@
+// vulkaninfo-style dump of every device the backend can see
+//
+//   device-info [--json] [--headless]
+//
+// falls back to headless (no surface formats / present modes) when no display is available
+
+use cranberries_backend::vulkan::features::DeviceFeature;
+use cranberries_backend::vulkan::instance::{Instance, InstanceDesc};
+use cranberries_backend::vulkan::physical_device::PhysicalDevice;
+use cranberries_backend::vulkan::surface::Surface;
+use cranberries_backend::vulkan::swapchain::Swapchain;
+
+use std::ffi::CStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use ash::vk;
+use serde_json::{json, Map, Value};
+
+const DEPTH_FORMATS: [vk::Format; 3] = [
+    vk::Format::D16_UNORM,
+    vk::Format::X8_D24_UNORM_PACK32,
+    vk::Format::D32_SFLOAT,
+];
+
+const DEPTH_STENCIL_FORMATS: [vk::Format; 3] = [
+    vk::Format::D16_UNORM_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+];
+
+struct Args {
+    json: bool,
+    headless: bool,
+}
+
+impl Args {
+    fn parse() -> Args {
+        let mut args = Args {
+            json: false,
+            headless: false,
+        };
+
+        for arg in std::env::args().skip(1) {
+            match arg.as_str() {
+                "--json" => args.json = true,
+                "--headless" => args.headless = true,
+                "-h" | "--help" => {
+                    println!("usage: device-info [--json] [--headless]");
+                    std::process::exit(0);
+                }
+                other => eprintln!("device-info: ignoring unknown argument `{}`", other),
+            }
+        }
+
+        args
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // the window only exists to create a surface, it is never shown
+    let window = if args.headless || !display_available() {
+        None
+    } else {
+        create_hidden_window()
+    };
+
+    let instance_desc = if window.is_some() {
+        InstanceDesc::windowed()
+    } else {
+        InstanceDesc::headless()
+    };
+    let instance = Arc::new(Instance::create(instance_desc.graphics_debugging(false))?);
+
+    let surface = match &window {
+        Some((_, window)) => match Surface::create(&instance, window) {
+            Ok(surface) => Some(surface),
+            Err(err) => {
+                eprintln!("device-info: failed to create a surface, continuing headless: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let devices = PhysicalDevice::enumerate(&instance, surface.as_ref())?
+        .iter()
+        .enumerate()
+        .map(|(index, pdevice)| device_info(&instance, pdevice, index, surface.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let info = json!({
+        "instance": {
+            "api_version": version_string(instance.api_version()),
+            "layers": instance.enabled_layers(),
+            "extensions": instance.enabled_extensions(),
+            "headless": surface.is_none(),
+        },
+        "devices": devices,
+    });
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        print_human(&info);
+    }
+
+    // the surface has to go before the instance, and the window after the surface
+    drop(surface);
+    drop(instance);
+    drop(window);
+
+    Ok(())
+}
+
+fn display_available() -> bool {
+    if cfg!(all(unix, not(target_os = "macos"), not(target_os = "android"))) {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    } else {
+        true
+    }
+}
+
+// winit 0.20 panics instead of returning an error when it can't reach the display server
+fn create_hidden_window() -> Option<(winit::event_loop::EventLoop<()>, winit::window::Window)> {
+    let event_loop = std::panic::catch_unwind(winit::event_loop::EventLoop::new).ok()?;
+
+    let window = winit::window::WindowBuilder::new()
+        .with_title("device-info")
+        .with_visible(false)
+        .build(&event_loop)
+        .ok()?;
+
+    Some((event_loop, window))
+}
+
+fn device_info(
+    instance: &Instance,
+    pdevice: &PhysicalDevice,
+    index: usize,
+    surface: Option<&Surface>,
+) -> Result<Value> {
+    let properties = pdevice.properties();
+
+    let queue_families = pdevice
+        .queue_families()
+        .iter()
+        .enumerate()
+        .map(|(family_index, family)| {
+            let present = match surface {
+                Some(surface) => json!(surface.supports_queue_family(pdevice.raw, family_index as u32)?),
+                None => Value::Null,
+            };
+            let granularity = family.min_image_transfer_granularity;
+
+            Ok(json!({
+                "index": family_index,
+                "flags": format!("{:?}", family.queue_flags),
+                "queue_count": family.queue_count,
+                "timestamp_valid_bits": family.timestamp_valid_bits,
+                "min_image_transfer_granularity": [granularity.width, granularity.height, granularity.depth],
+                "present": present,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut extensions = unsafe { instance.raw.enumerate_device_extension_properties(pdevice.raw)? }
+        .iter()
+        .map(|extension| {
+            let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+            (name.to_string_lossy().into_owned(), extension.spec_version)
+        })
+        .collect::<Vec<_>>();
+    extensions.sort();
+
+    let extensions = extensions
+        .into_iter()
+        .map(|(name, spec_version)| json!({ "name": name, "spec_version": spec_version }))
+        .collect::<Vec<_>>();
+
+    let memory_heaps = pdevice
+        .memory_heaps()
+        .iter()
+        .enumerate()
+        .map(|(heap_index, heap)| {
+            json!({
+                "index": heap_index,
+                "size": heap.size,
+                "size_mib": heap.size / (1024 * 1024),
+                "flags": format!("{:?}", heap.flags),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let memory_types = pdevice
+        .memory_types()
+        .iter()
+        .enumerate()
+        .map(|(type_index, memory_type)| {
+            json!({
+                "index": type_index,
+                "heap_index": memory_type.heap_index,
+                "flags": format!("{:?}", memory_type.property_flags),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let attachment_formats = |formats: &[vk::Format]| {
+        formats
+            .iter()
+            .filter(|&&format| {
+                pdevice.supports_format(format, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+            })
+            .map(|format| format!("{:?}", format))
+            .collect::<Vec<_>>()
+    };
+
+    let surface_info = match surface {
+        Some(surface) if queue_families.iter().any(|family| family["present"] == json!(true)) => {
+            let support = Swapchain::query_swapchain_support(pdevice.raw, surface)?;
+
+            json!({
+                "formats": support
+                    .formats
+                    .iter()
+                    .map(|format| json!({
+                        "format": format!("{:?}", format.format),
+                        "color_space": format!("{:?}", format.color_space),
+                    }))
+                    .collect::<Vec<_>>(),
+                "present_modes": support
+                    .present_modes
+                    .iter()
+                    .map(|mode| format!("{:?}", mode))
+                    .collect::<Vec<_>>(),
+                "min_image_count": support.capabilities.min_image_count,
+                "max_image_count": support.capabilities.max_image_count,
+            })
+        }
+        _ => Value::Null,
+    };
+
+    Ok(json!({
+        "index": index,
+        "name": pdevice.device_name(),
+        "type": format!("{:?}", pdevice.device_type()),
+        "api_version": version_string(pdevice.api_version()),
+        "driver_version": properties.driver_version,
+        "vendor_id": format!("{:#06x}", properties.vendor_id),
+        "device_id": format!("{:#06x}", properties.device_id),
+        "queue_families": queue_families,
+        "extensions": extensions,
+        "features": {
+            "core": core_features_to_json(pdevice.features()),
+            "vulkan_11": features_11_to_json(pdevice.features_11()),
+            "vulkan_12": features_12_to_json(pdevice.features_12()),
+        },
+        // what a `DeviceFeatureRequest` could enable on this device
+        "backend_features": DeviceFeature::ALL
+            .iter()
+            .filter(|feature| feature.is_supported(pdevice.supported_features()))
+            .map(|feature| format!("{:?}", feature))
+            .collect::<Vec<_>>(),
+        "limits": limits_to_json(pdevice.limits()),
+        "memory": {
+            "heaps": memory_heaps,
+            "types": memory_types,
+        },
+        "depth_formats": attachment_formats(&DEPTH_FORMATS),
+        "depth_stencil_formats": attachment_formats(&DEPTH_STENCIL_FORMATS),
+        "surface": surface_info,
+    }))
+}
+
+fn version_string(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        vk::api_version_major(version),
+        vk::api_version_minor(version),
+        vk::api_version_patch(version),
+    )
+}
+
+// ash has no serde support, so every field is listed by hand;
+// `json_fields!(value, to_json; a, b)` builds `{ "a": to_json(&value.a), "b": to_json(&value.b) }`
+macro_rules! json_fields {
+    ($value:expr, $to_json:expr; $($field:ident),* $(,)?) => {{
+        let mut fields = Map::new();
+        $(fields.insert(stringify!($field).to_owned(), $to_json(&$value.$field));)*
+        fields
+    }};
+}
+
+fn number<T: Copy + Into<Value>>(value: &T) -> Value {
+    (*value).into()
+}
+
+fn list<T: Copy + Into<Value>, const N: usize>(value: &[T; N]) -> Value {
+    value.to_vec().into()
+}
+
+fn flag(value: &vk::Bool32) -> Value {
+    Value::Bool(*value == vk::TRUE)
+}
+
+fn sample_counts(value: &vk::SampleCountFlags) -> Value {
+    Value::String(format!("{:?}", value))
+}
+
+fn limits_to_json(limits: &vk::PhysicalDeviceLimits) -> Value {
+    let mut fields = json_fields!(limits, number;
+        max_image_dimension1_d, max_image_dimension2_d, max_image_dimension3_d,
+        max_image_dimension_cube, max_image_array_layers, max_texel_buffer_elements,
+        max_uniform_buffer_range, max_storage_buffer_range, max_push_constants_size,
+        max_memory_allocation_count, max_sampler_allocation_count, buffer_image_granularity,
+        sparse_address_space_size, max_bound_descriptor_sets, max_per_stage_descriptor_samplers,
+        max_per_stage_descriptor_uniform_buffers, max_per_stage_descriptor_storage_buffers,
+        max_per_stage_descriptor_sampled_images, max_per_stage_descriptor_storage_images,
+        max_per_stage_descriptor_input_attachments, max_per_stage_resources,
+        max_descriptor_set_samplers, max_descriptor_set_uniform_buffers,
+        max_descriptor_set_uniform_buffers_dynamic, max_descriptor_set_storage_buffers,
+        max_descriptor_set_storage_buffers_dynamic, max_descriptor_set_sampled_images,
+        max_descriptor_set_storage_images, max_descriptor_set_input_attachments,
+        max_vertex_input_attributes, max_vertex_input_bindings, max_vertex_input_attribute_offset,
+        max_vertex_input_binding_stride, max_vertex_output_components,
+        max_tessellation_generation_level, max_tessellation_patch_size,
+        max_tessellation_control_per_vertex_input_components,
+        max_tessellation_control_per_vertex_output_components,
+        max_tessellation_control_per_patch_output_components,
+        max_tessellation_control_total_output_components,
+        max_tessellation_evaluation_input_components,
+        max_tessellation_evaluation_output_components, max_geometry_shader_invocations,
+        max_geometry_input_components, max_geometry_output_components,
+        max_geometry_output_vertices, max_geometry_total_output_components,
+        max_fragment_input_components, max_fragment_output_attachments,
+        max_fragment_dual_src_attachments, max_fragment_combined_output_resources,
+        max_compute_shared_memory_size, max_compute_work_group_invocations,
+        sub_pixel_precision_bits, sub_texel_precision_bits, mipmap_precision_bits,
+        max_draw_indexed_index_value, max_draw_indirect_count, max_sampler_lod_bias,
+        max_sampler_anisotropy, max_viewports, viewport_sub_pixel_bits, min_memory_map_alignment,
+        min_texel_buffer_offset_alignment, min_uniform_buffer_offset_alignment,
+        min_storage_buffer_offset_alignment, min_texel_offset, max_texel_offset,
+        min_texel_gather_offset, max_texel_gather_offset, min_interpolation_offset,
+        max_interpolation_offset, sub_pixel_interpolation_offset_bits, max_framebuffer_width,
+        max_framebuffer_height, max_framebuffer_layers, max_color_attachments,
+        max_sample_mask_words, timestamp_period, max_clip_distances, max_cull_distances,
+        max_combined_clip_and_cull_distances, discrete_queue_priorities, point_size_granularity,
+        line_width_granularity, optimal_buffer_copy_offset_alignment,
+        optimal_buffer_copy_row_pitch_alignment, non_coherent_atom_size,
+    );
+    fields.extend(json_fields!(limits, list;
+        max_compute_work_group_count, max_compute_work_group_size, max_viewport_dimensions,
+        viewport_bounds_range, point_size_range, line_width_range,
+    ));
+    fields.extend(json_fields!(limits, sample_counts;
+        framebuffer_color_sample_counts, framebuffer_depth_sample_counts,
+        framebuffer_stencil_sample_counts, framebuffer_no_attachments_sample_counts,
+        sampled_image_color_sample_counts, sampled_image_integer_sample_counts,
+        sampled_image_depth_sample_counts, sampled_image_stencil_sample_counts,
+        storage_image_sample_counts,
+    ));
+    fields.extend(json_fields!(limits, flag;
+        timestamp_compute_and_graphics, strict_lines, standard_sample_locations,
+    ));
+
+    Value::Object(fields)
+}
+
+fn core_features_to_json(features: &vk::PhysicalDeviceFeatures) -> Value {
+    Value::Object(json_fields!(features, flag;
+        robust_buffer_access, full_draw_index_uint32, image_cube_array, independent_blend,
+        geometry_shader, tessellation_shader, sample_rate_shading, dual_src_blend, logic_op,
+        multi_draw_indirect, draw_indirect_first_instance, depth_clamp, depth_bias_clamp,
+        fill_mode_non_solid, depth_bounds, wide_lines, large_points, alpha_to_one, multi_viewport,
+        sampler_anisotropy, texture_compression_etc2, texture_compression_astc_ldr,
+        texture_compression_bc, occlusion_query_precise, pipeline_statistics_query,
+        vertex_pipeline_stores_and_atomics, fragment_stores_and_atomics,
+        shader_tessellation_and_geometry_point_size, shader_image_gather_extended,
+        shader_storage_image_extended_formats, shader_storage_image_multisample,
+        shader_storage_image_read_without_format, shader_storage_image_write_without_format,
+        shader_uniform_buffer_array_dynamic_indexing, shader_sampled_image_array_dynamic_indexing,
+        shader_storage_buffer_array_dynamic_indexing, shader_storage_image_array_dynamic_indexing,
+        shader_clip_distance, shader_cull_distance, shader_float64, shader_int64, shader_int16,
+        shader_resource_residency, shader_resource_min_lod, sparse_binding,
+        sparse_residency_buffer, sparse_residency_image2_d, sparse_residency_image3_d,
+        sparse_residency2_samples, sparse_residency4_samples, sparse_residency8_samples,
+        sparse_residency16_samples, sparse_residency_aliased, variable_multisample_rate,
+        inherited_queries,
+    ))
+}
+
+fn features_11_to_json(features: &vk::PhysicalDeviceVulkan11Features) -> Value {
+    Value::Object(json_fields!(features, flag;
+        storage_buffer16_bit_access, uniform_and_storage_buffer16_bit_access,
+        storage_push_constant16, storage_input_output16, multiview, multiview_geometry_shader,
+        multiview_tessellation_shader, variable_pointers_storage_buffer, variable_pointers,
+        protected_memory, sampler_ycbcr_conversion, shader_draw_parameters,
+    ))
+}
+
+fn features_12_to_json(features: &vk::PhysicalDeviceVulkan12Features) -> Value {
+    Value::Object(json_fields!(features, flag;
+        sampler_mirror_clamp_to_edge, draw_indirect_count, storage_buffer8_bit_access,
+        uniform_and_storage_buffer8_bit_access, storage_push_constant8,
+        shader_buffer_int64_atomics, shader_shared_int64_atomics, shader_float16, shader_int8,
+        descriptor_indexing, shader_input_attachment_array_dynamic_indexing,
+        shader_uniform_texel_buffer_array_dynamic_indexing,
+        shader_storage_texel_buffer_array_dynamic_indexing,
+        shader_uniform_buffer_array_non_uniform_indexing,
+        shader_sampled_image_array_non_uniform_indexing,
+        shader_storage_buffer_array_non_uniform_indexing,
+        shader_storage_image_array_non_uniform_indexing,
+        shader_input_attachment_array_non_uniform_indexing,
+        shader_uniform_texel_buffer_array_non_uniform_indexing,
+        shader_storage_texel_buffer_array_non_uniform_indexing,
+        descriptor_binding_uniform_buffer_update_after_bind,
+        descriptor_binding_sampled_image_update_after_bind,
+        descriptor_binding_storage_image_update_after_bind,
+        descriptor_binding_storage_buffer_update_after_bind,
+        descriptor_binding_uniform_texel_buffer_update_after_bind,
+        descriptor_binding_storage_texel_buffer_update_after_bind,
+        descriptor_binding_update_unused_while_pending, descriptor_binding_partially_bound,
+        descriptor_binding_variable_descriptor_count, runtime_descriptor_array,
+        sampler_filter_minmax, scalar_block_layout, imageless_framebuffer,
+        uniform_buffer_standard_layout, shader_subgroup_extended_types,
+        separate_depth_stencil_layouts, host_query_reset, timeline_semaphore,
+        buffer_device_address, buffer_device_address_capture_replay,
+        buffer_device_address_multi_device, vulkan_memory_model, vulkan_memory_model_device_scope,
+        vulkan_memory_model_availability_visibility_chains, shader_output_viewport_index,
+        shader_output_layer, subgroup_broadcast_dynamic_id,
+    ))
+}
+
+fn print_human(info: &Value) {
+    print_value(info, 0);
+}
+
+fn print_value(value: &Value, depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                if is_inline(field) {
+                    println!("{}{}: {}", indent, name, inline_string(field));
+                } else {
+                    println!("{}{}:", indent, name);
+                    print_value(field, depth + 1);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                if is_inline(item) {
+                    println!("{}{}", indent, inline_string(item));
+                } else {
+                    println!("{}[{}]", indent, i);
+                    print_value(item, depth + 1);
+                }
+            }
+        }
+        other => println!("{}{}", indent, inline_string(other)),
+    }
+}
+
+// scalars and short arrays of scalars fit on one line
+fn is_inline(value: &Value) -> bool {
+    match value {
+        Value::Object(fields) => fields.is_empty(),
+        Value::Array(items) => items.len() <= 4 && items.iter().all(|item| !item.is_object() && !item.is_array()),
+        _ => true,
+    }
+}
+
+fn inline_string(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_owned(),
+        Value::String(text) => text.clone(),
+        Value::Object(_) => "{}".to_owned(),
+        Value::Array(items) => format!("[{}]", items.iter().map(inline_string).collect::<Vec<_>>().join(", ")),
+        other => other.to_string(),
+    }
+}
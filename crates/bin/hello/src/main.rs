@@ -1,29 +1,22 @@
-use cranberries_backend::vulkan::instance::{Instance};
-use cranberries_backend::vulkan::physical_device::{PhysicalDevice};
-use cranberries_backend::vulkan::logical_device::{Device, self};
-use cranberries_backend::vulkan::surface::Surface;
-use cranberries_backend::vulkan::swapchain::{Swapchain, SwapchainImage, SwapchainDesc};
-use cranberries_backend::vulkan::shader::{RenderPass, FramebufferCacheKey};
+use cranberries_backend::vulkan::{RenderBackend, RenderBackendConfig};
+use cranberries_backend::vulkan::logical_device::{DescriptorSet, Device};
+use cranberries_backend::vulkan::swapchain::SwapchainImage;
+use cranberries_backend::vulkan::shader::{Framebuffer, RenderPass, FramebufferCacheKey};
 use cranberries_backend::vulkan::shader::RenderPassDesc;
 use cranberries_backend::vulkan::shader::RenderPassAttachmentDesc;
-use cranberries_backend::vulkan::{buffer, shader};
+use cranberries_backend::vulkan::shader::{self, RasterPipeline};
 use cranberries_backend::vulkan::buffer::{Buffer, UniformBufferObject};
 // use cranberries_backend::vulkan::logical_device::CommandBuffer;
 // use cranberries_backend::vulkan::swapchain::SwapchainDesc;
 use cranberries_backend::vulkan::image::{ImageViewDesc, Image};
-
-use winit::event::{Event, VirtualKeyCode, ElementState, KeyboardInput, WindowEvent};
-use winit::event_loop::{EventLoop, ControlFlow};
+use cranberries_backend::vulkan::timeline::QueueType;
+use cranberries_backend::vulkan::error::BackendError;
 
 use cranberries_backend::constants;
-use cranberries_backend;
 use cranberries_backend::window::{self, WindowApp};
-use std::os::windows::raw;
 use std::sync::Arc;
 use cranberries_backend::constants::*;
-use ash::vk::{self, CommandBuffer, DescriptorSet, Handle};
-use std::{ptr, num, clone};
-use ash::vk::ImageViewType;
+use ash::vk;
 // use ash::version::DeviceV1_0;
 //use ash;
 
@@ -41,17 +34,11 @@ fn init_window(event_loop: &EventLoop<()>) -> winit::window::Window {
 
 
 struct TestApp{
-    _window: winit::window::Window,
-    _instance: Arc<Instance>,
-    _graphics_pipeline: ash::vk::Pipeline,
-    _pipeline_layout: ash::vk::PipelineLayout,
+    _graphics_pipeline: RasterPipeline,
     //_command_buffers: Vec<CommandBuffer>,
-    _physical_device: Arc<PhysicalDevice>,
     _logical_device: Arc<Device>,
-    _swapchain: Swapchain,
-    _framebuffers: Vec<ash::vk::Framebuffer>,
+    _framebuffers: Vec<Framebuffer>,
     _render_pass: Arc<RenderPass>,
-    _surface: Arc<Surface>,
     // image_available_semaphores: Vec<ash::vk::Semaphore>,
     // render_finished_semaphores: Vec<ash::vk::Semaphore>,
     // in_flight_fences: Vec<ash::vk::Fence>,
@@ -60,33 +47,32 @@ struct TestApp{
     _vertex_buffer: Buffer,
     _index_buffer: Buffer,
     _uniform_buffers: Vec<Buffer>,
-    _descriptor_sets: Vec<vk::DescriptorSet>,
+    _descriptor_sets: Vec<DescriptorSet>,
     _uniform_transform: UniformBufferObject,
     _texture_image: Image,
     _depth_image: Image,
+    // last, so every resource above is gone before the device and window
+    _backend: RenderBackend,
 }
 
 impl TestApp{
     pub fn new(event_loop: &winit::event_loop::EventLoop<()>) -> Self {
-        let _window = window::init_window(&event_loop, WINDOW_TITLE, WINDOW_WIDTH, WINDOW_HEIGHT);
-        let _instance = 
-            Arc::new(Instance::new().unwrap());
-        let _surface =
-            Arc::new(Surface::create(&_instance, &_window).unwrap());
-        let _physical_device = 
-            Arc::new(PhysicalDevice::create(&_instance, &_surface).unwrap());
-        let _logical_device = 
-            Arc::new(Device::create(&_instance, &_physical_device, &_surface,&constants::VALIDATION, &constants::DEVICE_EXTENSIONS).unwrap());
-        // let _swapchain =
-        //     Arc::new(Swapchain::create(&_instance, &_logical_device, &_physical_device, &_surface, &_physical_device.queue_family_index).unwrap());
-
-        let mut swapchain_desc: SwapchainDesc = Default::default();
-        swapchain_desc.dims = vk::Extent2D {
-            width: constants::WINDOW_WIDTH,
-            height: constants::WINDOW_HEIGHT,
-        };
-
-        let _swapchain = Swapchain::create(&_instance, &_logical_device, &_physical_device, &_surface, &_physical_device.queue_family_index, swapchain_desc).unwrap();
+        let _backend = RenderBackend::new(event_loop, RenderBackendConfig {
+            swapchain_extent: [WINDOW_WIDTH, WINDOW_HEIGHT],
+            vsync: false,
+            graphics_debugging: constants::VALIDATION.is_enable,
+            validation_features: Default::default(),
+            device_index: None,
+            device_name: None,
+            device_features: Default::default(),
+            device_extensions: Default::default(),
+            frames_in_flight: MAX_FRAMES_IN_FLIGHT,
+            crash_tracking: constants::VALIDATION.is_enable,
+        }).unwrap();
+        let _instance = &_backend.instance;
+        let _physical_device = &_backend.physical_device;
+        let _logical_device = _backend.device.clone();
+        let _swapchain = _backend.swapchain.as_ref().unwrap();
 
         // let _swapchain_imageviews = 
         //     Device::create_image_views(&_logical_device, _swapchain.swapchain_format, &_swapchain.swapchain_images, _swapchain.swapchain_dims);
@@ -161,25 +147,7 @@ impl TestApp{
         // }
 
         // new version 2
-        let mut depth_format = vk::Format::D32_SFLOAT;
-        let candidate_formats = [
-            vk::Format::D32_SFLOAT,
-            vk::Format::D32_SFLOAT_S8_UINT,
-            vk::Format::D24_UNORM_S8_UINT,];
-
-        for &format in candidate_formats.iter() {
-            let format_properties =
-                unsafe { _instance.raw.get_physical_device_format_properties(_physical_device.raw, format) };
-            if vk::ImageTiling::OPTIMAL == vk::ImageTiling::LINEAR
-                && format_properties.linear_tiling_features.contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
-            {
-                depth_format = format.clone();
-            } else if vk::ImageTiling::OPTIMAL == vk::ImageTiling::OPTIMAL
-                && format_properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
-            {
-                depth_format = format.clone();
-            }
-        }
+        let depth_format = _physical_device.best_depth_format(false).unwrap();
 
         let _render_pass = Arc::new(RenderPass::create_render_pass(
             &_logical_device,
@@ -191,6 +159,7 @@ impl TestApp{
                 ],
                 depth_attachment: Some(RenderPassAttachmentDesc::create_depth_attachment(depth_format)),
             },
+            Some("main render pass"),
         ).unwrap());
 
 
@@ -213,7 +182,7 @@ impl TestApp{
         // _render_pass.framebuffer_cache.get_or_create(&_logical_device.raw, key);
         
         //descriptor set
-        let _ubo_layout = _logical_device.create_descriptor_set_layout();
+        let _ubo_layout = _logical_device.create_descriptor_set_layout().unwrap();
 
         //image 
         let images = _swapchain.images.iter().map(|image| {
@@ -221,7 +190,7 @@ impl TestApp{
         })
         .collect::<Vec<_>>();
 
-        let image_views = _logical_device.create_image_views(_swapchain.desc.format.format, &images, _swapchain.desc.dims);
+        let image_views = _logical_device.create_image_views(_swapchain.desc.format.format, &images, _swapchain.desc.dims).unwrap();
 
         let mut image_desc = cranberries_backend::vulkan::image::ImageDesc::create(_swapchain.desc.format.format, cranberries_backend::vulkan::image::ImageType::Tex2d, [_swapchain.desc.dims.width, _swapchain.desc.dims.height,0]);
         image_desc.flags = vk::ImageCreateFlags::default();
@@ -234,27 +203,23 @@ impl TestApp{
         depth_image_desc.flags = vk::ImageCreateFlags::default();
         depth_image_desc.usage = vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
 
-        let device_memory_properties = unsafe {
-            _instance.raw.get_physical_device_memory_properties(_physical_device.raw)
-        };
-
         let _depth_image = _logical_device.create_image(depth_image_desc, 
             vk::MemoryPropertyFlags::DEVICE_LOCAL, 
-            &device_memory_properties).unwrap();
+            Some("depth image")).unwrap();
 
         let depth_image_view_desc = ImageViewDesc {
             view_type: Some(vk::ImageViewType::TYPE_2D),
             format: Some(depth_format),
             aspect_mask: vk::ImageAspectFlags::DEPTH,
-            base_mip_level: 0 as u32,
-            level_count: Some(1 as u32),
+            base_mip_level: 0,
+            level_count: Some(1),
         };
 
         //println!("depth_image_desc: {:#?}", depth_image_desc);
         //println!("depth_image_view_desc: {:#?}", depth_image_view_desc);
 
 
-        let depth_image_view = _logical_device.create_image_view(depth_image_view_desc, &_depth_image.desc, _depth_image.raw).unwrap();
+        let depth_image_view = _logical_device.create_image_view(depth_image_view_desc, &_depth_image.desc, _depth_image.raw, Some("depth image view")).unwrap();
 
         let _framebuffers = image_views.iter().map(|image_view| {
             let _framebuffer_cachekey = FramebufferCacheKey::new(
@@ -266,55 +231,55 @@ impl TestApp{
             _render_pass.framebuffer_cache.get_or_create(&_logical_device.raw, _framebuffer_cachekey, *image_view, depth_image_view, _render_pass.raw).unwrap()
         }).collect::<Vec<_>>();
 
-        let (_graphics_pipeline, _pipeline_layout) = shader::create_graphics_pipline(
+        let _graphics_pipeline = shader::create_graphics_pipline(
             &_logical_device, _render_pass.raw, _swapchain.desc.dims,
             _ubo_layout,
-        );
-
-        let physical_device_memory_properties =
-        unsafe { _instance.raw.get_physical_device_memory_properties(_physical_device.raw) };
-
-        let _texture_image = _logical_device.create_texture_image(&physical_device_memory_properties, &Path::new(constants::TEXTURE_PATH)).unwrap();
+            Some("textured quad pipeline"),
+        ).unwrap();
+
+        // texture, vertices and indices go up in one submission, preferably on the transfer queue
+        let mut upload_batch = _logical_device.begin_upload(QueueType::Transfer).unwrap();
+        let _texture_image = upload_batch.create_texture_image(Path::new(constants::TEXTURE_PATH)).unwrap();
+        let _vertex_buffer = upload_batch.create_vertex_buffer(&constants::RECT_TEX_COORD_VERTICES_DATA_2).unwrap();
+        let _index_buffer = upload_batch.create_index_buffer(&constants::RECT_TEX_COORD_INDICES_DATA_2).unwrap();
+        let upload = upload_batch.submit().unwrap();
         
         
-        let mut _texture_image_view_desc = ImageViewDesc::default();
-        _texture_image_view_desc.view_type = Some(vk::ImageViewType::TYPE_2D);
-        _texture_image_view_desc.format = Some(vk::Format::R8G8B8A8_SRGB);
-        _texture_image_view_desc.aspect_mask = vk::ImageAspectFlags::COLOR;
+        let _texture_image_view_desc = ImageViewDesc {
+            view_type: Some(vk::ImageViewType::TYPE_2D),
+            format: Some(vk::Format::R8G8B8A8_SRGB),
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            ..Default::default()
+        };
 
-        let _texture_image_view = _logical_device.create_image_view(_texture_image_view_desc, &_texture_image.desc, _texture_image.raw).unwrap();
+        let _texture_image_view = _logical_device.create_image_view(_texture_image_view_desc, &_texture_image.desc, _texture_image.raw, Some("texture image view")).unwrap();
         
         let _texture_sampler = _logical_device.create_texture_sampler().unwrap();
         
         
         
-        let _vertex_buffer = _logical_device.create_vertex_buffer(constants::RECT_TEX_COORD_VERTICES_DATA_2).unwrap();
-
-        let _index_buffer = _logical_device.create_index_buffer(constants::RECT_TEX_COORD_INDICES_DATA_2).unwrap();
+        // the rest of setup doesn't touch the uploaded data, so only wait here
+        upload.wait().unwrap();
+        drop(upload);
 
         let _uniform_buffers = _logical_device.create_uniform_buffer(_swapchain.images.len()).unwrap();
         
-        let _descriptor_pool = _logical_device.create_descriptor_pool(_swapchain.images.len());
+        let _descriptor_pool = _logical_device.create_descriptor_pool(_swapchain.images.len()).unwrap();
         let _descriptor_sets = _logical_device.create_descriptor_sets(
             _descriptor_pool, 
             _ubo_layout, 
             &_uniform_buffers,
             _texture_image_view,
             _texture_sampler, 
-            _swapchain.images.len()).unwrap();
+            _swapchain.images.len(),
+            Some("textured quad descriptor set")).unwrap();
 
         TestApp {
-            _window,
-            _instance,
             _graphics_pipeline,
-            _pipeline_layout,
             //_command_buffers,
-            _physical_device,
             _logical_device,
-            _swapchain,
             _framebuffers,
             _render_pass,
-            _surface,
             // image_available_semaphores: sync_objects.image_available_semaphores,
             // render_finished_semaphores: sync_objects.render_finished_semaphores,
             // in_flight_fences: sync_objects.inflight_fences,
@@ -346,18 +311,17 @@ impl TestApp{
                         0.1,
                         10.0,
                     );
-                    proj[1][1] = proj[1][1] * -1.0;
+                    proj[1][1] = -proj[1][1];
                     proj
                 },
             },
             _texture_image,
             _depth_image,
+            _backend,
         }
     }
 }
 
-const MAX_FRAMES_IN_FLIGHT: usize = 2;
-
 impl WindowApp for TestApp{
     // fn draw_frame(&mut self, delta_time: f32){
     //     //println!("draw_frame");
@@ -452,29 +416,28 @@ impl WindowApp for TestApp{
         //to do fix
         //println!("draw frame");
 
-        let current_frame = self._logical_device.begin_frame();
+        let current_frame = self._logical_device.begin_frame().unwrap();
         
         //let mut a = self._swapchain;
-        let result = self._swapchain.acquire_next_image();
-        let mut swapchain_image: SwapchainImage = match result {
+        let result = self._backend.swapchain.as_mut().unwrap().acquire_next_image(&current_frame);
+        let swapchain_image: SwapchainImage = match result {
             std::result::Result::Ok(res) => {
                 res
             },
-            std::result::Result::Err(vk_result) => match vk_result {
-                    vk::Result::ERROR_OUT_OF_DATE_KHR =>{ //} | vk::Result::SUBOPTIMAL_KHR=> {
+            std::result::Result::Err(err) => match BackendError::of(&err) {
+                    Some(BackendError::OutOfDate) =>{ //} | vk::Result::SUBOPTIMAL_KHR=> {
                         //self.recreate_swapchain();
                         return;
                     },
-                    _ => panic!("Failed to execute queue present."),
-                    _ => panic!("Failed to acquire Swap Chain Image!"),
+                    _ => panic!("Failed to acquire Swap Chain Image! {:?}", err),
             },
         };
 
         //println!("swapchain index {}", swapchain_image.image_index);
         self._logical_device.test_update_uniform_buffer(delta_time, 
-            self._swapchain.desc.dims, 
+            self._backend.swapchain.as_ref().unwrap().desc.dims, 
             &mut self._uniform_buffers[swapchain_image.image_index as usize],
-            &mut self._uniform_transform);
+            &mut self._uniform_transform).unwrap();
 
         //let _device = self._logical_device.clone();
 
@@ -541,21 +504,9 @@ impl WindowApp for TestApp{
         //     }
         // };        
 
-        let command_buffer = &current_frame.main_command_buffer;
         //let cb = self._logical_device.setup_cb.lock().unwrap();
 
-        unsafe {
-            // self._logical_device.raw
-            //     .reset_command_buffer(command_buffer.raw, vk::CommandBufferResetFlags::default())
-            //     .unwrap();
-
-            self._logical_device.raw
-                .begin_command_buffer(command_buffer.raw, 
-                    &vk::CommandBufferBeginInfo::builder()
-                        .flags(vk::CommandBufferUsageFlags::SIMULTANEOUS_USE),//ONE_TIME_SUBMIT
-                    )
-                    .unwrap();
-        }
+        // begin_frame has already reset the command buffer and started recording
 
         //let command_buffer = cb.raw; 
 
@@ -597,67 +548,28 @@ impl WindowApp for TestApp{
 
         // let framebuffer = self._render_pass.framebuffer_cache.get_or_create(&self._logical_device.raw, _framebuffer_cachekey, image_view).unwrap();
 
-        let render_pass_begin_info = ash::vk::RenderPassBeginInfo {
-            s_type: ash::vk::StructureType::RENDER_PASS_BEGIN_INFO,
-            p_next: ptr::null(),
-            render_pass: self._render_pass.raw,
-            //framebuffer: self._framebuffers[self._swapchain.next_semaphore as usize],
-            framebuffer: self._framebuffers[swapchain_image.image_index as usize],
-            render_area: ash::vk::Rect2D {
-                offset: ash::vk::Offset2D { x: 0, y: 0 },
-                extent: self._swapchain.desc.dims,
-            },
-            clear_value_count: clear_values.len() as u32,
-            p_clear_values: clear_values.as_ptr(),
-        };
-
-        unsafe {
-            self._logical_device.raw.cmd_begin_render_pass(
-                command_buffer.raw,
-                &render_pass_begin_info,
-                vk::SubpassContents::INLINE,
+        let mut recorder = self._logical_device.frame_recorder(&current_frame);
+        {
+            let mut main_pass_label = recorder.debug_label_scope("main pass");
+            main_pass_label.crash_marker("main pass");
+            let mut main_pass = main_pass_label.begin_render_pass(
+                &self._render_pass,
+                &self._framebuffers[swapchain_image.image_index as usize],
+                self._backend.swapchain.as_ref().unwrap().desc.dims,
+                &clear_values,
             );
-            self._logical_device.raw.cmd_bind_pipeline(
-                command_buffer.raw,
-                vk::PipelineBindPoint::GRAPHICS,
-                self._graphics_pipeline,
-            );
-
-            //vertex
-            let vertex_buffers = [self._vertex_buffer.raw];
-            let offsets = [0_u64];
-            let descriptor_sets_to_bind = [self._descriptor_sets[swapchain_image.image_index as usize]];
-
-            self._logical_device.raw.cmd_bind_vertex_buffers(command_buffer.raw, 0, &vertex_buffers, &offsets);
-            //vertex
 
-            //index
-            self._logical_device.raw.cmd_bind_index_buffer(command_buffer.raw, self._index_buffer.raw, 0, vk::IndexType::UINT32);
-            //index
-
-            // descriptor set
-            self._logical_device.raw.cmd_bind_descriptor_sets(
-                command_buffer.raw,
-                vk::PipelineBindPoint::GRAPHICS,
-                self._pipeline_layout,
-                0,
-                &descriptor_sets_to_bind,
-                &[],
-            );
-            //descriptor set
+            main_pass.bind_pipeline(&self._graphics_pipeline);
+            main_pass.bind_vertex_buffer(0, &self._vertex_buffer, 0);
+            main_pass.bind_index_buffer(&self._index_buffer, 0, vk::IndexType::UINT32);
+            main_pass
+                .bind_descriptor_set(0, &self._descriptor_sets[swapchain_image.image_index as usize])
+                .unwrap();
 
-            //index draw
-            self._logical_device.raw.cmd_draw_indexed(command_buffer.raw, constants::RECT_TEX_COORD_INDICES_DATA_2.len() as u32, 1, 0, 0, 0);
-
-            //draw
-           // self._logical_device.raw.cmd_draw(command_buffer.raw, constants::_VERTICES_DATA.len() as u32, 1, 0, 0);
-
-            self._logical_device.raw.cmd_end_render_pass(command_buffer.raw);
-        }
-
-        unsafe {
-            self._logical_device.raw.end_command_buffer(command_buffer.raw).unwrap();
+            main_pass.draw_indexed(constants::RECT_TEX_COORD_INDICES_DATA_2.len() as u32, 1, 0, 0, 0);
         }
+        // the frame's command buffer is ended by `finish_frame`
+        recorder.finish().unwrap();
 
         // let submit_infos = [vk::SubmitInfo {
         //     s_type: vk::StructureType::SUBMIT_INFO,
@@ -713,51 +625,10 @@ impl WindowApp for TestApp{
         
         //let presentation_cb = &current_frame.presentation_command_buffer;
 
-        let submit_infos = [vk::SubmitInfo {
-            s_type: vk::StructureType::SUBMIT_INFO,
-            p_next: ptr::null(),
-            wait_semaphore_count: 1 as u32,
-            p_wait_semaphores: std::slice::from_ref(&swapchain_image.acquire_semaphore).as_ptr(),
-            p_wait_dst_stage_mask: [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT].as_ptr(),
-            command_buffer_count: 1,
-            p_command_buffers: &command_buffer.raw,
-            signal_semaphore_count: 1 as u32,
-            p_signal_semaphores: std::slice::from_ref(&swapchain_image.rendering_finished_semaphore).as_ptr(),
-        }];
-        
-        unsafe {
-            // self._logical_device.raw
-            // .reset_command_buffer(presentation_cb.raw, vk::CommandBufferResetFlags::default())
-            // .unwrap();
-
-            // self._logical_device.raw
-            //     .begin_command_buffer(presentation_cb.raw, 
-            //         &vk::CommandBufferBeginInfo::builder()
-            //             .flags(vk::CommandBufferUsageFlags::SIMULTANEOUS_USE),
-            //         )
-            //         .unwrap();
-
-            // self._logical_device.raw.end_command_buffer(presentation_cb.raw).unwrap();
-
-            self._logical_device.raw
-                //.reset_fences(&[command_buffer.submit_done_fence])
-                .reset_fences(std::slice::from_ref(&command_buffer.submit_done_fence))
-                .expect("Failed to reset Fence!");
-
-            self._logical_device.raw
-                .queue_submit(
-                    self._logical_device._graphics_queue,
-                    &submit_infos,
-                    command_buffer.submit_done_fence,
-                )
-                .expect("Failed to execute queue submit.");
-        }
+        // ends the command buffer and submits it, waiting for the acquired image
+        self._logical_device.finish_frame(current_frame).unwrap();
 
-        //self._logical_device.with_setup_cb(callback).unwrap();
-
-        self._swapchain.present_image(swapchain_image);
-
-        self._logical_device.finish_frame(current_frame);
+        self._backend.swapchain.as_ref().unwrap().present_image(swapchain_image).unwrap();
 
     }
 
@@ -795,7 +666,7 @@ impl WindowApp for TestApp{
         // })
         // .collect::<Vec<_>>();
 
-        // let image_views = self._logical_device.create_image_views(_swapchain.desc.format.format, &images, _swapchain.desc.dims);
+        // let image_views = self._logical_device.create_image_views(_swapchain.desc.format.format, &images, _swapchain.desc.dims).unwrap();
 
         // let mut image_desc = cranberries_backend::vulkan::image::ImageDesc::new(_swapchain.desc.format.format, cranberries_backend::vulkan::image::ImageType::Tex2d, [_swapchain.desc.dims.width, _swapchain.desc.dims.height,0]);
         // image_desc.flags = vk::ImageCreateFlags::default();
@@ -839,16 +710,17 @@ impl WindowApp for TestApp{
     }
 
     fn window_ref(&self) -> &winit::window::Window{
-        &self._window
+        self._backend.window.as_ref().unwrap()
     }
 }
 
+/*
 struct SyncObjects {
     image_available_semaphores: Vec<ash::vk::Semaphore>,
     render_finished_semaphores: Vec<ash::vk::Semaphore>,
     inflight_fences: Vec<ash::vk::Fence>,
 }
-/*
+
 impl TestApp {
     pub fn create_sync_objects(device: &ash::Device) -> SyncObjects {
         let mut sync_objects = SyncObjects {
@@ -917,7 +789,7 @@ impl TestApp {
         //         .expect("Failed to begin recording Command Buffer at beginning!");
         // }
 
-        let current_frame = self._logical_device.begin_frame();
+        let current_frame = self._logical_device.begin_frame().unwrap();
 
         let command_buffer = current_frame.main_command_buffer.raw;
 
@@ -982,7 +854,7 @@ fn test01() {
     let event_loop = EventLoop::new();
     let _window = init_window(&event_loop);
 
-    let _backend = cranberries_backend::vulkan::instance::Instance::new().unwrap();
+    let _backend = cranberries_backend::vulkan::instance::Instance::create(Default::default()).unwrap();
     main_loop(event_loop);
     */
 
@@ -1,6 +1,5 @@
 //use ash::vk_make_version;
 use crate::debug::ValidationInfo;
-use crate::debug::DeviceExtension;
 
 // pub const APPLICATION_VERSION: u32 = vk_make_version!(1, 0, 0);
 // pub const ENGINE_VERSION: u32 = vk_make_version!(1, 0, 0);
@@ -10,10 +9,12 @@ use crate::debug::DeviceExtension;
 
 // vk::ApplicationInfo::builder().api_version(vk::make_api_version(0, 1, 2, 0));
 
-pub const WINDOW_TITLE: &'static str = "cranberries";
+pub const WINDOW_TITLE: &str = "cranberries";
 pub const WINDOW_WIDTH: u32 = 1920;
 pub const WINDOW_HEIGHT: u32 = 1080;
 
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
 
 pub const VALIDATION: ValidationInfo = ValidationInfo {
     is_enable: true,
@@ -22,9 +23,7 @@ pub const VALIDATION: ValidationInfo = ValidationInfo {
 
 //VK_LAYER_KHRONOS_validation
 //VK_LAYER_RENDERDOC_Capture
-pub const DEVICE_EXTENSIONS: DeviceExtension = DeviceExtension {
-    names: ["VK_KHR_swapchain"],
-};
+pub const DEVICE_EXTENSIONS: [&str; 1] = ["VK_KHR_swapchain"];
 
 
 
@@ -110,7 +109,7 @@ pub const RECT_VERTICES_DATA: [Vertex; 4] = [
 // ];
 
 
-pub const TEXTURE_PATH: &'static str = "crates/assets/texture.jpg";
+pub const TEXTURE_PATH: &str = "crates/assets/texture.jpg";
 
 pub const RECT_TEX_COORD_VERTICES_DATA_2: [Vertex; 8] = [
     Vertex {
@@ -1,67 +1,531 @@
+use anyhow::Result;
 use ash::vk;
 
 use std::ffi::CStr;
 use std::os::raw::c_void;
 use std::ptr;
 use std::os::raw::c_char;
+use std::fmt;
+use std::slice;
+use std::sync::{Arc, Mutex, PoisonError};
 
+use crate::vulkan::error::BackendError;
+
+// one message from the debug messenger, with the objects and labels the layer attached to it
+#[derive(Clone, Debug)]
+pub struct DebugMessage {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    pub message_id_name: Option<String>,
+    pub message_id_number: i32,
+    pub message: String,
+    pub objects: Vec<DebugObject>,
+    pub queue_labels: Vec<String>,
+    pub cmd_buf_labels: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DebugObject {
+    pub object_type: vk::ObjectType,
+    pub handle: u64,
+    pub name: Option<String>,
+}
+
+impl DebugMessage {
+    unsafe fn from_raw(
+        severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+        data: &vk::DebugUtilsMessengerCallbackDataEXT,
+    ) -> DebugMessage {
+        let labels = |labels: *const vk::DebugUtilsLabelEXT, count: u32| -> Vec<String> {
+            if labels.is_null() {
+                return Vec::new();
+            }
+            slice::from_raw_parts(labels, count as usize)
+                .iter()
+                .filter_map(|label| cstr_to_string(label.p_label_name))
+                .collect()
+        };
+
+        let objects = if data.p_objects.is_null() {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(data.p_objects, data.object_count as usize)
+                .iter()
+                .map(|object| DebugObject {
+                    object_type: object.object_type,
+                    handle: object.object_handle,
+                    name: cstr_to_string(object.p_object_name),
+                })
+                .collect()
+        };
+
+        DebugMessage {
+            severity,
+            message_type,
+            message_id_name: cstr_to_string(data.p_message_id_name),
+            message_id_number: data.message_id_number,
+            message: cstr_to_string(data.p_message).unwrap_or_default(),
+            objects,
+            queue_labels: labels(data.p_queue_labels, data.queue_label_count),
+            cmd_buf_labels: labels(data.p_cmd_buf_labels, data.cmd_buf_label_count),
+        }
+    }
+}
+
+impl fmt::Display for DebugMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => "[Verbose]",
+            vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => "[Warning]",
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => "[Error]",
+            vk::DebugUtilsMessageSeverityFlagsEXT::INFO => "[Info]",
+            _ => "[Unknown]",
+        };
+        let types = match self.message_type {
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL => "[General]",
+            vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE => "[Performance]",
+            vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "[Validation]",
+            _ => "[Unknown]",
+        };
+        write!(f, "[Debug]{}{}", severity, types)?;
+        if let Some(id_name) = &self.message_id_name {
+            write!(f, "[{}]", id_name)?;
+        }
+        write!(f, " {}", self.message)?;
+
+        for object in self.objects.iter() {
+            write!(f, "\n    object {:?} 0x{:x}", object.object_type, object.handle)?;
+            if let Some(name) = &object.name {
+                write!(f, " \"{}\"", name)?;
+            }
+        }
+        if !self.queue_labels.is_empty() {
+            write!(f, "\n    queue labels: {}", self.queue_labels.join(" > "))?;
+        }
+        if !self.cmd_buf_labels.is_empty() {
+            write!(f, "\n    command buffer labels: {}", self.cmd_buf_labels.join(" > "))?;
+        }
+        std::result::Result::Ok(())
+    }
+}
+
+unsafe fn cstr_to_string(raw: *const c_char) -> Option<String> {
+    if raw.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(raw).to_string_lossy().into_owned())
+    }
+}
+
+// where debug messenger messages end up
+#[derive(Clone)]
+pub enum DebugSink {
+    Stdout,
+    // `log` crate macros, tracing users can pick them up through tracing-log; nothing shows up
+    // until the app installs a logger
+    Log,
+    Callback(Arc<dyn Fn(&DebugMessage) + Send + Sync>),
+}
+
+impl DebugSink {
+    pub fn callback(callback: impl Fn(&DebugMessage) + Send + Sync + 'static) -> Self {
+        DebugSink::Callback(Arc::new(callback))
+    }
+
+    fn send(&self, message: &DebugMessage) {
+        match self {
+            DebugSink::Stdout => println!("{}", message),
+            DebugSink::Log => {
+                let level = if message.severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+                    log::Level::Error
+                } else if message.severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+                    log::Level::Warn
+                } else if message.severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+                    log::Level::Info
+                } else {
+                    log::Level::Trace
+                };
+                log::log!(target: "vulkan", level, "{}", message);
+            }
+            DebugSink::Callback(callback) => callback(message),
+        }
+    }
+}
+
+// collects every messenger message regardless of the sink's severity and type mask, so tests
+// can assert on it
+#[derive(Default)]
+pub struct ValidationCapture {
+    messages: Mutex<Vec<DebugMessage>>,
+    // kept until the next `take`, which panics with it on the caller's thread
+    first_error: Mutex<Option<DebugMessage>>,
+    panic_on_error: bool,
+}
+
+impl ValidationCapture {
+    pub fn new(panic_on_error: bool) -> Self {
+        ValidationCapture {
+            messages: Mutex::new(Vec::new()),
+            first_error: Mutex::new(None),
+            panic_on_error,
+        }
+    }
+
+    // panics on the first error since the last call when created with `panic_on_error`
+    pub fn take(&self) -> Vec<DebugMessage> {
+        let messages = std::mem::take(&mut *self.messages.lock().unwrap_or_else(PoisonError::into_inner));
+        // the guard has to be gone before panicking, a poisoned lock would abort in `push`
+        let first_error = self.first_error.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(error) = first_error {
+            if self.panic_on_error {
+                panic!("Validation error: {}", error);
+            }
+        }
+        messages
+    }
+
+    // runs inside the layer's callback, panicking there would abort the process
+    fn push(&self, message: &DebugMessage) {
+        if message.severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+            self.first_error
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert_with(|| message.clone());
+        }
+
+        self.messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(message.clone());
+    }
+}
+
+// one `debugPrintfEXT` call from a shader
+#[derive(Clone, Debug)]
+pub struct DebugPrintfRecord {
+    // only known when the layer runs with `printf_verbose`, which prefixes the stage
+    pub stage: Option<vk::ShaderStageFlags>,
+    pub text: String,
+}
+
+impl DebugPrintfRecord {
+    fn is_printf(message: &DebugMessage) -> bool {
+        message
+            .message_id_name
+            .as_ref()
+            .is_some_and(|id_name| id_name.contains("DEBUG-PRINTF"))
+    }
+
+    fn parse(message: &DebugMessage) -> DebugPrintfRecord {
+        // the layer puts "Object 0: ... | MessageID = 0x.. | " in front of the shader output,
+        // which may contain " | " itself
+        let body = message
+            .message
+            .find("MessageID = ")
+            .and_then(|id| {
+                let rest = &message.message[id..];
+                rest.find(" | ").map(|start| &rest[start + 3..])
+            })
+            .unwrap_or(message.message.as_str());
+
+        let stage = body.find("Stage = ").and_then(|start| {
+            let stage = &body[start + "Stage = ".len()..];
+            let end = stage.find(|c: char| !c.is_alphanumeric()).unwrap_or(stage.len());
+            match &stage[..end] {
+                "Vertex" => Some(vk::ShaderStageFlags::VERTEX),
+                "TessellationControl" => Some(vk::ShaderStageFlags::TESSELLATION_CONTROL),
+                "TessellationEvaluation" => Some(vk::ShaderStageFlags::TESSELLATION_EVALUATION),
+                "Geometry" => Some(vk::ShaderStageFlags::GEOMETRY),
+                "Fragment" => Some(vk::ShaderStageFlags::FRAGMENT),
+                "Compute" => Some(vk::ShaderStageFlags::COMPUTE),
+                "RayGeneration" => Some(vk::ShaderStageFlags::RAYGEN_KHR),
+                "AnyHit" => Some(vk::ShaderStageFlags::ANY_HIT_KHR),
+                "ClosestHit" => Some(vk::ShaderStageFlags::CLOSEST_HIT_KHR),
+                "Miss" => Some(vk::ShaderStageFlags::MISS_KHR),
+                "Intersection" => Some(vk::ShaderStageFlags::INTERSECTION_KHR),
+                "Callable" => Some(vk::ShaderStageFlags::CALLABLE_KHR),
+                "Task" => Some(vk::ShaderStageFlags::TASK_NV),
+                "Mesh" => Some(vk::ShaderStageFlags::MESH_NV),
+                _ => None,
+            }
+        });
+
+        // verbose output ends its preamble with "... generated at line N." before the text
+        let text = match body.find("printf message generated at line") {
+            Some(start) => {
+                let rest = &body[start..];
+                match rest.find('.') {
+                    Some(end) => &rest[end + 1..],
+                    None => rest,
+                }
+            }
+            None => body,
+        };
+
+        DebugPrintfRecord {
+            stage,
+            text: text.trim().to_owned(),
+        }
+    }
+}
+
+pub type DebugPrintfCallback = Arc<dyn Fn(&DebugPrintfRecord) + Send + Sync>;
+
+// shader printf output, kept apart from the validation messages
+#[derive(Default)]
+pub struct DebugPrintfCapture {
+    records: Mutex<Vec<DebugPrintfRecord>>,
+    callback: Option<DebugPrintfCallback>,
+}
+
+impl DebugPrintfCapture {
+    // records are handed to `callback` instead of being collected
+    pub fn with_callback(callback: impl Fn(&DebugPrintfRecord) + Send + Sync + 'static) -> Self {
+        DebugPrintfCapture {
+            records: Mutex::new(Vec::new()),
+            callback: Some(Arc::new(callback)),
+        }
+    }
+
+    pub fn take(&self) -> Vec<DebugPrintfRecord> {
+        std::mem::take(&mut *self.records.lock().unwrap())
+    }
+
+    fn push(&self, record: DebugPrintfRecord) {
+        match &self.callback {
+            Some(callback) => callback(&record),
+            None => self.records.lock().unwrap().push(record),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DebugMessengerDesc {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    // `messageIdNumber`s to drop, e.g. known false positives
+    pub suppressed_message_ids: Vec<i32>,
+    pub sink: DebugSink,
+    // keeps every message that isn't suppressed, whatever the severity and type masks, drained
+    // with `Instance::take_validation_messages`
+    pub capture: Option<Arc<ValidationCapture>>,
+    // receives debugPrintf output, which then skips the sink and the severity mask
+    pub debug_printf: Option<Arc<DebugPrintfCapture>>,
+}
+
+impl Default for DebugMessengerDesc {
+    fn default() -> Self {
+        DebugMessengerDesc {
+            severity: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            message_type: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            suppressed_message_ids: Vec::new(),
+            sink: DebugSink::Log,
+            capture: None,
+            debug_printf: None,
+        }
+    }
+}
+
+impl DebugMessengerDesc {
+    pub fn severity(mut self, severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn message_type(mut self, message_type: vk::DebugUtilsMessageTypeFlagsEXT) -> Self {
+        self.message_type = message_type;
+        self
+    }
+
+    pub fn suppress_message_id(mut self, message_id: i32) -> Self {
+        self.suppressed_message_ids.push(message_id);
+        self
+    }
+
+    pub fn sink(mut self, sink: DebugSink) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn capture_validation(mut self, panic_on_error: bool) -> Self {
+        self.capture = Some(Arc::new(ValidationCapture::new(panic_on_error)));
+        self
+    }
+
+    pub fn debug_printf(mut self, debug_printf: DebugPrintfCapture) -> Self {
+        self.debug_printf = Some(Arc::new(debug_printf));
+        self
+    }
+
+    fn is_suppressed(&self, message: &DebugMessage) -> bool {
+        self.suppressed_message_ids.contains(&message.message_id_number)
+    }
+
+    fn accepts(&self, message: &DebugMessage) -> bool {
+        self.severity.intersects(message.severity)
+            && self.message_type.intersects(message.message_type)
+            && !self.is_suppressed(message)
+    }
+}
+
+// `p_user_data` points at the `DebugMessengerDesc` boxed inside `Instance`
 unsafe extern "system" fn vulkan_debug_utils_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
-    let severity = match message_severity {
-        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => "[Verbose]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => "[Warning]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => "[Error]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => "[Info]",
-        _ => "[Unknown]",
-    };
-    let types = match message_type {
-        vk::DebugUtilsMessageTypeFlagsEXT::GENERAL => "[General]",
-        vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE => "[Performance]",
-        vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "[Validation]",
-        _ => "[Unknown]",
-    };
-    let message = CStr::from_ptr((*p_callback_data).p_message);
-    println!("[Debug]{}{}{:?}", severity, types, message);
+    if p_callback_data.is_null() || p_user_data.is_null() {
+        return vk::FALSE;
+    }
+    let desc = &*(p_user_data as *const DebugMessengerDesc);
+
+    let message = DebugMessage::from_raw(message_severity, message_type, &*p_callback_data);
+
+    if let Some(debug_printf) = &desc.debug_printf {
+        if DebugPrintfRecord::is_printf(&message) {
+            debug_printf.push(DebugPrintfRecord::parse(&message));
+            return vk::FALSE;
+        }
+    }
+
+    if desc.accepts(&message) {
+        desc.sink.send(&message);
+    }
+    if let Some(capture) = &desc.capture {
+        if !desc.is_suppressed(&message) {
+            capture.push(&message);
+        }
+    }
 
     vk::FALSE
 }
 
+// extra checks of the Khronos validation layer, through VK_EXT_validation_features
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValidationFeatures {
+    pub synchronization: bool,
+    pub best_practices: bool,
+    pub gpu_assisted: bool,
+    pub debug_printf: bool,
+}
+
+impl ValidationFeatures {
+    pub fn any(&self) -> bool {
+        self.synchronization || self.best_practices || self.gpu_assisted || self.debug_printf
+    }
+
+    pub fn to_vk(self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        let mut features = Vec::new();
+        if self.synchronization {
+            features.push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        if self.best_practices {
+            features.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+        }
+        if self.gpu_assisted {
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+        }
+        if self.debug_printf {
+            features.push(vk::ValidationFeatureEnableEXT::DEBUG_PRINTF);
+        }
+        features
+    }
+}
+
 pub struct ValidationInfo {
     pub is_enable: bool,
     pub required_validation_layers: [&'static str; 1],
 }
 
+// required extensions reject devices that lack them, optional ones are enabled when available
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct DeviceExtension {
-    pub names: [&'static str; 1],
-    //    pub raw_names: [*const i8; 1],
+    pub names: Vec<String>,
+    pub optional_names: Vec<String>,
 }
 
 impl DeviceExtension {
-    pub fn get_extensions_raw_names(&self) -> [*const c_char; 1] {
-        [
-            // currently just enable the Swapchain extension.
-            ash::extensions::khr::Swapchain::name().as_ptr(),
-        ]
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // what presenting to a surface needs, see `constants::DEVICE_EXTENSIONS`
+    pub fn windowed() -> Self {
+        crate::constants::DEVICE_EXTENSIONS
+            .iter()
+            .fold(DeviceExtension::new(), |extensions, name| extensions.require(name))
+    }
+
+    pub fn require(mut self, name: &str) -> Self {
+        if !self.names.iter().any(|n| n == name) {
+            self.names.push(name.to_owned());
+        }
+        self
+    }
+
+    pub fn optional(mut self, name: &str) -> Self {
+        if !self.optional_names.iter().any(|n| n == name) {
+            self.optional_names.push(name.to_owned());
+        }
+        self
+    }
+
+    pub fn extend(self, other: &DeviceExtension) -> Self {
+        let extensions = other.names.iter().fold(self, |extensions, name| extensions.require(name));
+        other.optional_names.iter().fold(extensions, |extensions, name| extensions.optional(name))
+    }
+
+    pub fn missing_required(&self, available: &[String]) -> Vec<String> {
+        self.names
+            .iter()
+            .filter(|name| !available.contains(name))
+            .cloned()
+            .collect()
+    }
+
+    // the list to enable: every required name plus the optional ones in `available`
+    pub fn resolve(&self, available: &[String]) -> anyhow::Result<Vec<String>> {
+        let missing = self.missing_required(available);
+        if !missing.is_empty() {
+            anyhow::bail!(BackendError::UnsupportedFeature(format!(
+                "missing required device extensions: {}",
+                missing.join(", ")
+            )));
+        }
+
+        let mut enabled = self.names.clone();
+        for name in self.optional_names.iter() {
+            if enabled.contains(name) {
+                continue;
+            }
+            if available.contains(name) {
+                enabled.push(name.clone());
+            } else {
+                log::warn!("Optional device extension {} is not available, skipping", name);
+            }
+        }
+
+        Ok(enabled)
     }
 }
 
 pub fn check_validation_layer_support(
     entry: &ash::Entry,
     required_validation_layers: &Vec<&str>,
-) -> bool {
+) -> Result<bool> {
     // if support validation layer, then return true
 
-    let layer_properties = entry
-        .enumerate_instance_layer_properties()
-        .expect("Failed to enumerate Instance Layers Properties");
+    let layer_properties = entry.enumerate_instance_layer_properties()?;
 
-    if layer_properties.len() <= 0 {
-        eprintln!("No available layers.");
-        return false;
+    if layer_properties.is_empty() {
+        log::warn!("No available layers.");
+        return Ok(false);
     }
 
     for required_layer_name in required_validation_layers.iter() {
@@ -75,49 +539,182 @@ pub fn check_validation_layer_support(
             }
         }
 
-        if is_layer_found == false {
-            return false;
+        if !is_layer_found {
+            return Ok(false);
         }
     }
 
-    true
+    Ok(true)
 }
 
 pub fn setup_debug_utils(
     is_enable_debug: bool,
     entry: &ash::Entry,
     instance: &ash::Instance,
-) -> (ash::extensions::ext::DebugUtils, vk::DebugUtilsMessengerEXT) {
+    desc: &DebugMessengerDesc,
+) -> Result<(ash::extensions::ext::DebugUtils, vk::DebugUtilsMessengerEXT)> {
     let debug_utils_loader = ash::extensions::ext::DebugUtils::new(entry, instance);
 
-    if is_enable_debug == false {
-        (debug_utils_loader, ash::vk::DebugUtilsMessengerEXT::null())
+    if !is_enable_debug {
+        Ok((debug_utils_loader, ash::vk::DebugUtilsMessengerEXT::null()))
     } else {
-        let messenger_ci = populate_debug_messenger_create_info();
+        let messenger_ci = populate_debug_messenger_create_info(desc);
 
-        let utils_messenger = unsafe {
-            debug_utils_loader
-                .create_debug_utils_messenger(&messenger_ci, None)
-                .expect("Debug Utils Callback")
-        };
+        let utils_messenger = unsafe { debug_utils_loader.create_debug_utils_messenger(&messenger_ci, None)? };
 
-        (debug_utils_loader, utils_messenger)
+        Ok((debug_utils_loader, utils_messenger))
     }
 }
 
-pub fn populate_debug_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT {
+// `desc` must stay at the same address for as long as the messenger lives
+pub fn populate_debug_messenger_create_info(desc: &DebugMessengerDesc) -> vk::DebugUtilsMessengerCreateInfoEXT {
+    let mut message_severity = desc.severity;
+    let mut message_type = desc.message_type;
+    // printf output arrives as INFO, `accepts` still filters it for everything else
+    if desc.debug_printf.is_some() {
+        message_severity |= vk::DebugUtilsMessageSeverityFlagsEXT::INFO;
+    }
+    // capture sees everything, the sink still only gets what `accepts`
+    if desc.capture.is_some() {
+        message_severity = vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
+            | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+            | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+            | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR;
+        message_type = vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+            | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+            | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE;
+    }
+
     vk::DebugUtilsMessengerCreateInfoEXT {
         s_type: vk::StructureType::DEBUG_UTILS_MESSENGER_CREATE_INFO_EXT,
         p_next: ptr::null(),
         flags: vk::DebugUtilsMessengerCreateFlagsEXT::empty(),
-        message_severity: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING |
-            // vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE |
-            // vk::DebugUtilsMessageSeverityFlagsEXT::INFO |
-            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
-        message_type: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-            | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
-            | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+        message_severity,
+        message_type,
         pfn_user_callback: Some(vulkan_debug_utils_callback),
-        p_user_data: ptr::null_mut(),
+        p_user_data: desc as *const DebugMessengerDesc as *mut c_void,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printf_message(message: &str) -> DebugMessage {
+        DebugMessage {
+            severity: vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
+            message_type: vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            message_id_name: Some("UNASSIGNED-DEBUG-PRINTF".to_owned()),
+            message_id_number: 0x4fe1fef9,
+            message: message.to_owned(),
+            objects: Vec::new(),
+            queue_labels: Vec::new(),
+            cmd_buf_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn capture_survives_a_panicking_take() {
+        let capture = ValidationCapture::new(true);
+        let error = DebugMessage {
+            severity: vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            message_id_name: Some("VUID-vkCmdDraw-None-02859".to_owned()),
+            ..printf_message("bad draw")
+        };
+
+        capture.push(&error);
+        let taken = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| capture.take()));
+        assert!(taken.is_err());
+
+        // the callback keeps working, and the next error is reported again
+        capture.push(&error);
+        assert!(!capture.first_error.is_poisoned());
+        let taken = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| capture.take()));
+        assert!(taken.is_err());
+        assert!(capture.take().is_empty());
+    }
+
+    #[test]
+    fn printf_parse_strips_layer_prefix() {
+        let message = printf_message(
+            "Object 0: handle = 0x1, type = VK_OBJECT_TYPE_QUEUE; | MessageID = 0x4fe1fef9 | value = 42",
+        );
+        assert!(DebugPrintfRecord::is_printf(&message));
+
+        let record = DebugPrintfRecord::parse(&message);
+        assert_eq!(record.stage, None);
+        assert_eq!(record.text, "value = 42");
+    }
+
+    #[test]
+    fn printf_parse_keeps_separators_in_text() {
+        let message = printf_message(
+            "Object 0: handle = 0x1, type = VK_OBJECT_TYPE_QUEUE; | MessageID = 0x4fe1fef9 | a | b | c",
+        );
+        assert_eq!(DebugPrintfRecord::parse(&message).text, "a | b | c");
+    }
+
+    #[test]
+    fn printf_parse_verbose_stage() {
+        let message = printf_message(
+            "Object 0: handle = 0x1, type = VK_OBJECT_TYPE_QUEUE; | MessageID = 0x4fe1fef9 | \
+             Command buffer (0x2). Draw Index 0. Pipeline (0x3). Shader Module (0x4). \
+             Shader Instruction Index = 120. Stage = Fragment. Fragment coord (x,y) = (0.5, 0.5). \
+             Debug shader printf message generated at line 12. color | 1.0",
+        );
+
+        let record = DebugPrintfRecord::parse(&message);
+        assert_eq!(record.stage, Some(vk::ShaderStageFlags::FRAGMENT));
+        assert_eq!(record.text, "color | 1.0");
+    }
+
+    #[test]
+    fn printf_parse_without_prefix() {
+        let record = DebugPrintfRecord::parse(&printf_message("plain text"));
+        assert_eq!(record.text, "plain text");
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn extension_builders_deduplicate() {
+        let extensions = DeviceExtension::new()
+            .require("VK_KHR_swapchain")
+            .require("VK_KHR_swapchain")
+            .optional("VK_EXT_memory_budget")
+            .extend(&DeviceExtension::new().require("VK_KHR_swapchain").optional("VK_EXT_memory_budget"));
+
+        assert_eq!(extensions.names, names(&["VK_KHR_swapchain"]));
+        assert_eq!(extensions.optional_names, names(&["VK_EXT_memory_budget"]));
+    }
+
+    #[test]
+    fn extension_resolve_skips_unavailable_optional() {
+        let extensions = DeviceExtension::new()
+            .require("VK_KHR_swapchain")
+            .optional("VK_EXT_memory_budget")
+            .optional("VK_AMD_buffer_marker")
+            // already required, not enabled twice
+            .optional("VK_KHR_swapchain");
+        let available = names(&["VK_AMD_buffer_marker", "VK_KHR_swapchain"]);
+
+        assert_eq!(
+            extensions.resolve(&available).unwrap(),
+            names(&["VK_KHR_swapchain", "VK_AMD_buffer_marker"])
+        );
+    }
+
+    #[test]
+    fn extension_resolve_fails_on_missing_required() {
+        let extensions = DeviceExtension::new()
+            .require("VK_KHR_swapchain")
+            .require("VK_KHR_synchronization2");
+        let available = names(&["VK_KHR_swapchain"]);
+
+        assert_eq!(extensions.missing_required(&available), names(&["VK_KHR_synchronization2"]));
+        let err = extensions.resolve(&available).unwrap_err();
+        assert!(matches!(BackendError::of(&err), Some(BackendError::UnsupportedFeature(_))));
     }
 }
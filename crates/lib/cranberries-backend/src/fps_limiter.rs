@@ -13,13 +13,19 @@ pub struct FPSLimiter {
     delta_frame: u32,
 }
 
+impl Default for FPSLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FPSLimiter {
     pub fn new() -> FPSLimiter {
         const DEFAULT_PREFER_FPS: f32 = 60.0;
 
         FPSLimiter {
             counter: Instant::now(),
-            frame_time_prefer: (1000_000.0_f32 / DEFAULT_PREFER_FPS) as u32,
+            frame_time_prefer: (1_000_000.0_f32 / DEFAULT_PREFER_FPS) as u32,
             samples: [0; SAMPLE_COUNT],
             current_frame: 0,
             delta_frame: 0,
@@ -27,7 +33,7 @@ impl FPSLimiter {
     }
 
     pub fn set_prefer_fps(&mut self, prefer_fps: f32) {
-        self.frame_time_prefer = (1000_000.0_f32 / prefer_fps) as u32;
+        self.frame_time_prefer = (1_000_000.0_f32 / prefer_fps) as u32;
     }
 
     /// Call this function in game loop to update its inner status.
@@ -56,12 +62,12 @@ impl FPSLimiter {
             sum += val;
         });
 
-        1000_000.0_f32 / (sum as f32 / SAMPLE_COUNT_FLOAT)
+        1_000_000.0_f32 / (sum as f32 / SAMPLE_COUNT_FLOAT)
     }
 
     /// Return current delta time in seconds
     /// this function ignore its second part, since the second is mostly zero.
     pub fn delta_time(&self) -> f32 {
-        self.delta_frame as f32 / 1000_000.0_f32 // time in second
+        self.delta_frame as f32 / 1_000_000.0_f32 // time in second
     }
 }
@@ -1,6 +1,7 @@
 
 //use ash::version::{EntryV1_0, InstanceV1_0};
 use ash::vk;
+use std::ffi::CStr;
 
 #[cfg(target_os = "windows")]
 use ash::extensions::khr::Win32Surface;
@@ -9,7 +10,6 @@ use ash::extensions::khr::XlibSurface;
 #[cfg(target_os = "macos")]
 use ash::extensions::mvk::MacOSSurface;
 
-use ash::extensions::ext::DebugUtils;
 use ash::extensions::khr::Surface;
 
 #[cfg(target_os = "macos")]
@@ -21,40 +21,40 @@ use metal::CoreAnimationLayer;
 #[cfg(target_os = "macos")]
 use objc::runtime::YES;
 
+// surface extensions, debug utils is requested separately by `InstanceDesc`
 // required extension ------------------------------------------------------
 #[cfg(target_os = "macos")]
-pub fn required_extension_names() -> Vec<*const i8> {
+pub fn required_extension_names() -> Vec<&'static CStr> {
     vec![
-        Surface::name().as_ptr(),
-        MacOSSurface::name().as_ptr(),
-        DebugUtils::name().as_ptr(),
+        Surface::name(),
+        MacOSSurface::name(),
     ]
 }
 
-#[cfg(all(windows))]
-pub fn required_extension_names() -> Vec<*const i8> {
+#[cfg(windows)]
+pub fn required_extension_names() -> Vec<&'static CStr> {
     vec![
-        Surface::name().as_ptr(),
-        Win32Surface::name().as_ptr(),
-        DebugUtils::name().as_ptr(),
+        Surface::name(),
+        Win32Surface::name(),
     ]
 }
 
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
-pub fn required_extension_names() -> Vec<*const i8> {
+pub fn required_extension_names() -> Vec<&'static CStr> {
     vec![
-        Surface::name().as_ptr(),
-        XlibSurface::name().as_ptr(),
-        DebugUtils::name().as_ptr(),
+        Surface::name(),
+        XlibSurface::name(),
     ]
 }
 // ------------------------------------------------------------------------
 
 // create surface ---------------------------------------------------------
+/// # Safety
+/// `window` must outlive the returned surface.
 #[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
-pub unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
-    entry: &E,
-    instance: &I,
+pub unsafe fn create_surface(
+    entry: &ash::Entry,
+    instance: &ash::Instance,
     window: &winit::window::Window,
 ) -> Result<vk::SurfaceKHR, vk::Result> {
     use std::ptr;
@@ -73,10 +73,12 @@ pub unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     xlib_surface_loader.create_xlib_surface(&x11_create_info, None)
 }
 
+/// # Safety
+/// `window` must outlive the returned surface.
 #[cfg(target_os = "macos")]
-pub unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
-    entry: &E,
-    instance: &I,
+pub unsafe fn create_surface(
+    entry: &ash::Entry,
+    instance: &ash::Instance,
     window: &winit::window::Window,
 ) -> Result<vk::SurfaceKHR, vk::Result> {
     use std::mem;
@@ -1,7 +1,10 @@
+use anyhow::Result;
 use std::ffi::CStr;
 use std::os::raw::c_char;
 use std::path::Path;
 
+use crate::vulkan::error::BackendError;
+
 /// Helper function to convert [c_char; SIZE] to string
 pub fn vk_to_string(raw_string_array: &[c_char]) -> String {
     // Implementation 1
@@ -28,19 +31,15 @@ pub fn vk_to_string(raw_string_array: &[c_char]) -> String {
         CStr::from_ptr(pointer)
     };
 
-    raw_string
-        .to_str()
-        .expect("Failed to convert vulkan raw string.")
-        .to_owned()
+    // drivers have shipped names that aren't valid UTF-8, don't let that take the app down
+    raw_string.to_string_lossy().into_owned()
 }
 
-pub fn read_shader_code(shader_path: &Path) -> Vec<u8> {
-    use std::fs::File;
-    use std::io::Read;
-
-    let spv_file =
-        File::open(shader_path).expect(&format!("Failed to find spv file at {:?}", shader_path));
-    let bytes_code: Vec<u8> = spv_file.bytes().filter_map(|byte| byte.ok()).collect();
+pub fn read_shader_code(shader_path: &Path) -> Result<Vec<u8>> {
+    let code = std::fs::read(shader_path).map_err(|err| BackendError::MissingFile {
+        path: shader_path.to_owned(),
+        kind: err.kind(),
+    })?;
 
-    bytes_code
+    Ok(code)
 }
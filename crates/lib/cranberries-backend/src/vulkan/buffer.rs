@@ -5,9 +5,11 @@ use anyhow::{Ok, Result};
 use memoffset::offset_of;
 use std::ptr;
 use std::sync::Arc;
-use cgmath::{Deg, Matrix4, Point3, Vector3};
+use cgmath::Matrix4;
 
-use crate::{constants, vulkan::logical_device::Device};
+use crate::vulkan::logical_device::{Device, ReleaseQueue};
+use crate::vulkan::timeline::QueueType;
+use crate::vulkan::error::BackendError;
 
 
 #[repr(C)]
@@ -124,44 +126,9 @@ impl Device {
         //     self.raw.unmap_memory(vertex_buffer_memory);
         // }
         
-        let buffer_size = std::mem::size_of_val(&vertex_data);
-
-        let staging_buffer_desc = BufferDesc {
-            size: buffer_size,
-            usage: vk::BufferUsageFlags::TRANSFER_SRC,
-            required_memory_flags: vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-            alignment: None,
-        };
-
-        let mut staging_buffer = self.create_buffer(staging_buffer_desc, None).unwrap();
-
-        unsafe {
-            let data_ptr = self.raw
-            .map_memory(
-                staging_buffer.device_memory,
-                0,
-                buffer_size as u64,
-                vk::MemoryMapFlags::empty(),
-            )
-            .expect("Failed to Map Memory") as *mut Vertex;
-
-            data_ptr.copy_from_nonoverlapping(vertex_data.as_ptr(), vertex_data.len());
-
-            self.raw.unmap_memory(staging_buffer.device_memory);
-        }
-
-        let vertex_buffer_desc = BufferDesc {
-            size: std::mem::size_of_val(&vertex_data),
-            usage: vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
-            required_memory_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            alignment: None,
-        };
-
-        let vertex_buffer = self.create_buffer(vertex_buffer_desc, None).unwrap();
-
-        self.copy_buffer(&staging_buffer, &vertex_buffer);
-
-        self.immediate_destroy_buffer(&mut staging_buffer);
+        let mut batch = self.begin_upload(QueueType::Transfer)?;
+        let vertex_buffer = batch.create_vertex_buffer(&vertex_data)?;
+        batch.submit()?.wait()?;
 
         Ok(vertex_buffer)
     }
@@ -170,44 +137,9 @@ impl Device {
         &self,
         index_data: [u32; 12],
     ) ->Result<Buffer> {
-        let buffer_size = std::mem::size_of_val(&index_data);
-
-        let staging_buffer_desc = BufferDesc {
-            size: buffer_size,
-            usage: vk::BufferUsageFlags::TRANSFER_SRC,
-            required_memory_flags: vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-            alignment: None,
-        };
-
-        let mut staging_buffer = self.create_buffer(staging_buffer_desc, None).unwrap();
-
-        unsafe {
-            let data_ptr = self.raw
-            .map_memory(
-                staging_buffer.device_memory,
-                0,
-                buffer_size as u64,
-                vk::MemoryMapFlags::empty(),
-            )
-            .expect("Failed to Map Memory") as *mut u32;
-
-            data_ptr.copy_from_nonoverlapping(index_data.as_ptr(), index_data.len());
-
-            self.raw.unmap_memory(staging_buffer.device_memory);
-        }
-
-        let index_buffer_desc = BufferDesc {
-            size: buffer_size,
-            usage: vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::INDEX_BUFFER,
-            required_memory_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            alignment: None,
-        };
-
-        let index_buffer = self.create_buffer(index_buffer_desc, None).unwrap();
-
-        self.copy_buffer(&staging_buffer, &index_buffer);
-
-        self.immediate_destroy_buffer(&mut staging_buffer);
+        let mut batch = self.begin_upload(QueueType::Transfer)?;
+        let index_buffer = batch.create_index_buffer(&index_data)?;
+        batch.submit()?.wait()?;
 
         Ok(index_buffer)
     }
@@ -227,8 +159,8 @@ impl Device {
             alignment: None,
         };
 
-        for _ in 0..swapchain_image_count {
-            let uniform_buffer = self.create_buffer(uniform_buffer_desc, None).unwrap();
+        for i in 0..swapchain_image_count {
+            let uniform_buffer = self.create_buffer(uniform_buffer_desc, Some(&format!("uniform buffer {}", i)), None)?;
             uniform_buffers.push(uniform_buffer);
         }
 
@@ -237,11 +169,11 @@ impl Device {
 
     pub fn test_update_uniform_buffer (
         &self,
-        delta_time: f32,
-        extent: vk::Extent2D,
+        _delta_time: f32,
+        _extent: vk::Extent2D,
         uniform_buffer: &mut Buffer,
         uniform_transform: &mut UniformBufferObject,
-    ) {
+    ) -> Result<()> {
         // let uniform_transform = UniformBufferObject {
         //     model: Matrix4::from_axis_angle(Vector3::new(0.0, 0.0, 1.0), Deg(90.0) * delta_time) * uniform_transform.model,
         //     proj: uniform_transform.proj,
@@ -265,7 +197,7 @@ impl Device {
         //     ),
         // }];
 
-        let ubos = [uniform_transform.clone()];
+        let ubos = [*uniform_transform];
 
         let buffer_size = (std::mem::size_of::<UniformBufferObject>() * ubos.len()) as u64;
 
@@ -276,20 +208,21 @@ impl Device {
                 0,
                 buffer_size as u64,
                 vk::MemoryMapFlags::empty(),
-            )
-            .expect("Failed to Map Memory") as *mut UniformBufferObject;
+            )? as *mut UniformBufferObject;
 
             data_ptr.copy_from_nonoverlapping(ubos.as_ptr(), ubos.len());
 
             self.raw.unmap_memory(uniform_buffer.device_memory);
         }
+
+        Ok(())
     }
 
     fn find_memory_type(
         type_filter: u32,
         required_properties: vk::MemoryPropertyFlags,
         mem_properties: vk::PhysicalDeviceMemoryProperties,
-    ) -> u32 {
+    ) -> std::result::Result<u32, BackendError> {
         for (i, memory_type) in mem_properties.memory_types.iter().enumerate() {
             //if (type_filter & (1 << i)) > 0 && (memory_type.property_flags & required_properties) == required_properties {
             //    return i as u32
@@ -299,11 +232,14 @@ impl Device {
             if (type_filter & (1 << i)) > 0
                 && memory_type.property_flags.contains(required_properties)
             {
-                return i as u32;
+                return std::result::Result::Ok(i as u32);
             }
         }
 
-        panic!("Failed to find suitable memory type!")
+        Err(BackendError::NoSuitableMemoryType {
+            type_bits: type_filter,
+            flags: required_properties,
+        })
     }
 }
 
@@ -314,6 +250,8 @@ pub struct Buffer {
     pub device_memory: vk::DeviceMemory,
     //pub allocation: gpu_allocator::SubAllocation,
     pub device: ash::Device,
+    // dropped buffers are destroyed once the frames that might use them are done
+    pub(crate) release_queue: Arc<ReleaseQueue>,
 }
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
@@ -329,7 +267,7 @@ impl Device {
     pub fn create_buffer_impl(
         &self,
         desc: BufferDesc,
-        //name: &str,
+        name: Option<&str>,
     ) -> Result<Buffer> {
         let buffer_info = vk::BufferCreateInfo {
             size: desc.size as u64,
@@ -338,10 +276,7 @@ impl Device {
             ..Default::default()
         };
 
-        let buffer = unsafe {
-            self.raw.create_buffer(&buffer_info, None)
-                .expect("Failed to create buffer")
-        };
+        let buffer = unsafe { self.raw.create_buffer(&buffer_info, None)? };
 
         let mut requirements = unsafe { self.raw.get_buffer_memory_requirements(buffer) };
 
@@ -355,8 +290,7 @@ impl Device {
             requirements.alignment = requirements.alignment.max(64);
         }
 
-        let mem_properties =
-        unsafe { self.instance.raw.get_physical_device_memory_properties(self.pdevice.raw) };
+        let mem_properties = *self.pdevice.memory_properties();
         
         //to do
         // let required_memory_flags: vk::MemoryPropertyFlags =
@@ -367,6 +301,13 @@ impl Device {
             desc.required_memory_flags,
             mem_properties,
         );
+        let memory_type = match memory_type {
+            std::result::Result::Ok(memory_type) => memory_type,
+            Err(err) => {
+                unsafe { self.raw.destroy_buffer(buffer, None) };
+                return Err(err.into());
+            }
+        };
 
         let allocate_info = vk::MemoryAllocateInfo {
             s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
@@ -378,70 +319,81 @@ impl Device {
         let buffer_memory = unsafe {
             self.raw
                 .allocate_memory(&allocate_info, None)
-                .expect("Failed to allocate vertex buffer memory!")
+                .inspect_err(|_| self.raw.destroy_buffer(buffer, None))?
         };
 
         unsafe {
-            self.raw.bind_buffer_memory(buffer, buffer_memory, 0)
-                .expect("bind_buffer_memory")
+            self.raw
+                .bind_buffer_memory(buffer, buffer_memory, 0)
+                .inspect_err(|_| {
+                    self.raw.destroy_buffer(buffer, None);
+                    self.raw.free_memory(buffer_memory, None);
+                })?
         };
 
+        if let Some(name) = name {
+            self.set_debug_name(buffer, name);
+            self.set_debug_name(buffer_memory, name);
+        }
+
         Ok(Buffer {
             raw: buffer,
             desc,
             device_memory: buffer_memory,
             device: self.raw.clone(),
+            release_queue: self.release_queue.clone(),
         })
     }
 
     pub fn create_buffer(
         &self,
         mut desc: BufferDesc,
+        name: Option<&str>,
         initial_data: Option<&[u8]>,
     ) -> Result<Buffer> {
         if initial_data.is_some() {
             desc.usage |= vk::BufferUsageFlags::TRANSFER_DST;
         }
 
-        let buffer = self.create_buffer_impl(desc)?;
+        let buffer = self.create_buffer_impl(desc, name)?;
 
         Ok(buffer)
     }
 
-    pub fn immediate_destroy_buffer(&self, buffer: &mut Buffer) {
-        drop(buffer);
-        // unsafe {
-        //     self.raw.destroy_buffer(buffer.raw, None);
-        // }
+    // skips the deferred release, for setup-time buffers the GPU is known to be done with,
+    // e.g. staging buffers once their `Upload` has completed
+    pub fn immediate_destroy_buffer(&self, mut buffer: Buffer) {
+        unsafe {
+            self.raw.destroy_buffer(buffer.raw, None);
+            self.raw.free_memory(buffer.device_memory, None);
+        }
+        buffer.raw = vk::Buffer::null();
+        buffer.device_memory = vk::DeviceMemory::null();
     }
 
     pub fn copy_buffer(
         &self,
         src_buffer: &Buffer,
         dst_buffer: &Buffer,
-    ) {
-        self.with_setup_cb(|cb| unsafe {
-            let copy_regions = [vk::BufferCopy {
-                src_offset: 0,
-                dst_offset: 0,
-                size: src_buffer.desc.size as u64,
-            }];
-            
-            self.raw.cmd_copy_buffer(cb, 
-                src_buffer.raw, 
-                dst_buffer.raw, 
-                &copy_regions);
-        }).unwrap();
+    ) -> Result<()> {
+        // both buffers stay with the graphics family
+        let mut batch = self.begin_upload(QueueType::Graphics)?;
+        batch.copy_buffer(src_buffer, dst_buffer);
+        batch.submit()?.wait()
     }
-    
 }
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        unsafe {
-            self.device.destroy_buffer(self.raw, None);
-
-            self.device.free_memory(self.device_memory, None);
+        // already gone through `Device::immediate_destroy_buffer`
+        if self.raw == vk::Buffer::null() {
+            return;
         }
+
+        let (raw, device_memory) = (self.raw, self.device_memory);
+        self.release_queue.defer(|pending| {
+            pending.buffers.push(raw);
+            pending.memory.push(device_memory);
+        });
     }
 }
\ No newline at end of file
@@ -0,0 +1,648 @@
+use anyhow::Result;
+use ash::vk;
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::thread::ThreadId;
+
+use crate::vulkan::buffer::Buffer;
+use crate::vulkan::error::BackendError;
+use crate::vulkan::logical_device::{CommandBuffer, DebugLabelScope, DescriptorSet, Device, DeviceFrame};
+use crate::vulkan::shader::{Framebuffer, RenderPass, ShaderPipelineCommon};
+
+// a command buffer in the recording state; commands can only be recorded while one exists,
+// and draws only inside a `RenderPassScope`
+#[must_use = "dropping the recorder ends the command buffer"]
+pub struct CommandRecorder<'a> {
+    device: &'a Device,
+    raw: vk::CommandBuffer,
+    // frame command buffers are begun by `begin_frame` and ended by `finish_frame`
+    owns_recording: bool,
+    finished: bool,
+    state: BoundState,
+    // buffers from `begin_frame_command_buffer` come out of the calling thread's pool, which
+    // only that thread may record from
+    _not_send: PhantomData<*const ()>,
+}
+
+// what's currently bound, so repeated binds of the same object are skipped
+#[derive(Default)]
+struct BoundState {
+    pipeline: Option<(vk::Pipeline, vk::PipelineLayout, vk::PipelineBindPoint)>,
+    vertex_buffers: Vec<Option<(vk::Buffer, u64)>>,
+    index_buffer: Option<(vk::Buffer, u64, vk::IndexType)>,
+    descriptor_sets: Vec<Option<vk::DescriptorSet>>,
+    viewport: Option<[f32; 6]>,
+    scissor: Option<vk::Rect2D>,
+}
+
+impl Device {
+    // begins `command_buffer`, which the GPU must be done with; the recorder ends it on
+    // `finish` or drop
+    pub fn begin_recording<'a>(&'a self, command_buffer: &'a CommandBuffer) -> Result<CommandRecorder<'a>> {
+        unsafe {
+            self.raw.begin_command_buffer(
+                command_buffer.raw,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+        }
+
+        Ok(CommandRecorder {
+            device: self,
+            raw: command_buffer.raw,
+            owns_recording: true,
+            finished: false,
+            state: Default::default(),
+            _not_send: PhantomData,
+        })
+    }
+
+    // records into the main command buffer of a frame from `begin_frame`, `finish_frame` ends it
+    pub fn frame_recorder<'a>(&'a self, frame: &'a DeviceFrame) -> CommandRecorder<'a> {
+        CommandRecorder {
+            device: self,
+            raw: frame.main_command_buffer.raw,
+            owns_recording: false,
+            finished: false,
+            state: Default::default(),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<'a> CommandRecorder<'a> {
+    // for commands the recorder doesn't wrap; bindings made through it aren't tracked
+    pub fn raw(&self) -> vk::CommandBuffer {
+        self.raw
+    }
+
+    pub fn device(&self) -> &'a Device {
+        self.device
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        if self.owns_recording {
+            unsafe { self.device.raw.end_command_buffer(self.raw)? };
+        }
+        Ok(())
+    }
+
+    // record the labelled commands through the returned scope
+    pub fn debug_label_scope(&mut self, name: &str) -> RecorderLabelScope<'_, 'a, Self> {
+        let label = self.device.debug_label_scope(self.raw, name);
+        RecorderLabelScope {
+            recorder: self,
+            _label: label,
+        }
+    }
+
+    pub fn insert_debug_label(&self, name: &str) {
+        self.device.insert_debug_label(self.raw, name);
+    }
+
+    // breadcrumb for `Device::crash_report`, does nothing unless crash tracking is enabled
+    pub fn crash_marker(&mut self, name: &str) {
+        self.device.record_crash_marker(self.raw, name);
+    }
+
+    pub fn begin_render_pass(
+        &mut self,
+        render_pass: &RenderPass,
+        framebuffer: &Framebuffer,
+        extent: vk::Extent2D,
+        clear_values: &[vk::ClearValue],
+    ) -> RenderPassScope<'_, 'a> {
+        self.cmd_begin_render_pass(render_pass, framebuffer, extent, clear_values, vk::SubpassContents::INLINE);
+
+        RenderPassScope {
+            recorder: self,
+            ends_render_pass: true,
+        }
+    }
+
+    // for a pass whose contents come from `SecondaryRecorder`s
+    pub fn begin_render_pass_with_secondaries(
+        &mut self,
+        render_pass: &RenderPass,
+        framebuffer: &Framebuffer,
+        extent: vk::Extent2D,
+        clear_values: &[vk::ClearValue],
+    ) -> SecondaryPassScope<'_, 'a> {
+        self.cmd_begin_render_pass(
+            render_pass,
+            framebuffer,
+            extent,
+            clear_values,
+            vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
+        );
+
+        SecondaryPassScope { recorder: self }
+    }
+
+    fn cmd_begin_render_pass(
+        &mut self,
+        render_pass: &RenderPass,
+        framebuffer: &Framebuffer,
+        extent: vk::Extent2D,
+        clear_values: &[vk::ClearValue],
+        contents: vk::SubpassContents,
+    ) {
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass.raw)
+            .framebuffer(framebuffer.raw)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(clear_values);
+
+        unsafe {
+            self.device
+                .raw
+                .cmd_begin_render_pass(self.raw, &render_pass_begin_info, contents);
+        }
+    }
+
+    pub fn bind_pipeline(&mut self, pipeline: &ShaderPipelineCommon) {
+        let bound = (pipeline.pipeline, pipeline.pipeline_layout, pipeline.pipeline_bind_point);
+        if self.state.pipeline == Some(bound) {
+            return;
+        }
+
+        // sets bound through another layout may be disturbed, so rebind them next time
+        if self.state.pipeline.is_none_or(|(_, layout, _)| layout != pipeline.pipeline_layout) {
+            self.state.descriptor_sets.clear();
+        }
+        self.state.pipeline = Some(bound);
+
+        unsafe {
+            self.device
+                .raw
+                .cmd_bind_pipeline(self.raw, pipeline.pipeline_bind_point, pipeline.pipeline);
+        }
+    }
+
+    // binds through the layout of the bound pipeline
+    pub fn bind_descriptor_set(&mut self, set_index: u32, descriptor_set: &DescriptorSet) -> Result<()> {
+        let descriptor_set = descriptor_set.raw;
+        let (_, layout, bind_point) = match self.state.pipeline {
+            Some(pipeline) => pipeline,
+            None => anyhow::bail!(BackendError::UnsupportedFeature(format!(
+                "descriptor set {} bound before any pipeline",
+                set_index
+            ))),
+        };
+
+        let slot = set_index as usize;
+        if self.state.descriptor_sets.get(slot) == Some(&Some(descriptor_set)) {
+            return Ok(());
+        }
+        if self.state.descriptor_sets.len() <= slot {
+            self.state.descriptor_sets.resize(slot + 1, None);
+        }
+        self.state.descriptor_sets[slot] = Some(descriptor_set);
+
+        unsafe {
+            self.device.raw.cmd_bind_descriptor_sets(
+                self.raw,
+                bind_point,
+                layout,
+                set_index,
+                &[descriptor_set],
+                &[],
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn bind_vertex_buffer(&mut self, binding: u32, buffer: &Buffer, offset: u64) {
+        let slot = binding as usize;
+        if self.state.vertex_buffers.get(slot) == Some(&Some((buffer.raw, offset))) {
+            return;
+        }
+        if self.state.vertex_buffers.len() <= slot {
+            self.state.vertex_buffers.resize(slot + 1, None);
+        }
+        self.state.vertex_buffers[slot] = Some((buffer.raw, offset));
+
+        unsafe {
+            self.device
+                .raw
+                .cmd_bind_vertex_buffers(self.raw, binding, &[buffer.raw], &[offset]);
+        }
+    }
+
+    pub fn bind_index_buffer(&mut self, buffer: &Buffer, offset: u64, index_type: vk::IndexType) {
+        let bound = (buffer.raw, offset, index_type);
+        if self.state.index_buffer == Some(bound) {
+            return;
+        }
+        self.state.index_buffer = Some(bound);
+
+        unsafe {
+            self.device
+                .raw
+                .cmd_bind_index_buffer(self.raw, buffer.raw, offset, index_type);
+        }
+    }
+
+    // only for pipelines with a dynamic viewport
+    pub fn set_viewport(&mut self, viewport: vk::Viewport) {
+        let key = [
+            viewport.x,
+            viewport.y,
+            viewport.width,
+            viewport.height,
+            viewport.min_depth,
+            viewport.max_depth,
+        ];
+        if self.state.viewport == Some(key) {
+            return;
+        }
+        self.state.viewport = Some(key);
+
+        unsafe {
+            self.device.raw.cmd_set_viewport(self.raw, 0, &[viewport]);
+        }
+    }
+
+    // only for pipelines with a dynamic scissor
+    pub fn set_scissor(&mut self, scissor: vk::Rect2D) {
+        if self.state.scissor == Some(scissor) {
+            return;
+        }
+        self.state.scissor = Some(scissor);
+
+        unsafe {
+            self.device.raw.cmd_set_scissor(self.raw, 0, &[scissor]);
+        }
+    }
+
+    pub fn pipeline_barrier(
+        &mut self,
+        src_stage: vk::PipelineStageFlags,
+        dst_stage: vk::PipelineStageFlags,
+        buffer_barriers: &[vk::BufferMemoryBarrier],
+        image_barriers: &[vk::ImageMemoryBarrier],
+    ) {
+        unsafe {
+            self.device.raw.cmd_pipeline_barrier(
+                self.raw,
+                src_stage,
+                dst_stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                buffer_barriers,
+                image_barriers,
+            );
+        }
+    }
+
+    pub fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer, regions: &[vk::BufferCopy]) {
+        unsafe {
+            self.device
+                .raw
+                .cmd_copy_buffer(self.raw, src.raw, dst.raw, regions);
+        }
+    }
+
+    pub fn dispatch(&mut self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+        unsafe {
+            self.device
+                .raw
+                .cmd_dispatch(self.raw, group_count_x, group_count_y, group_count_z);
+        }
+    }
+}
+
+impl Drop for CommandRecorder<'_> {
+    fn drop(&mut self) {
+        if self.owns_recording && !self.finished {
+            unsafe {
+                let _ = self.device.raw.end_command_buffer(self.raw);
+            }
+        }
+    }
+}
+
+// a debug label around the commands recorded through it; it borrows the recorder, so the label
+// is closed before the recording can be finished
+pub struct RecorderLabelScope<'r, 'a, R> {
+    recorder: &'r mut R,
+    _label: DebugLabelScope<'a>,
+}
+
+impl<R> Deref for RecorderLabelScope<'_, '_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        self.recorder
+    }
+}
+
+impl<R> DerefMut for RecorderLabelScope<'_, '_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        self.recorder
+    }
+}
+
+// ends its render pass on drop; only commands valid inside a render pass are exposed
+pub struct RenderPassScope<'r, 'a> {
+    recorder: &'r mut CommandRecorder<'a>,
+    // secondaries continue a pass their primary began and ends
+    ends_render_pass: bool,
+}
+
+impl RenderPassScope<'_, '_> {
+    pub fn bind_pipeline(&mut self, pipeline: &ShaderPipelineCommon) {
+        self.recorder.bind_pipeline(pipeline);
+    }
+
+    pub fn bind_descriptor_set(&mut self, set_index: u32, descriptor_set: &DescriptorSet) -> Result<()> {
+        self.recorder.bind_descriptor_set(set_index, descriptor_set)
+    }
+
+    pub fn bind_vertex_buffer(&mut self, binding: u32, buffer: &Buffer, offset: u64) {
+        self.recorder.bind_vertex_buffer(binding, buffer, offset);
+    }
+
+    pub fn bind_index_buffer(&mut self, buffer: &Buffer, offset: u64, index_type: vk::IndexType) {
+        self.recorder.bind_index_buffer(buffer, offset, index_type);
+    }
+
+    pub fn set_viewport(&mut self, viewport: vk::Viewport) {
+        self.recorder.set_viewport(viewport);
+    }
+
+    pub fn set_scissor(&mut self, scissor: vk::Rect2D) {
+        self.recorder.set_scissor(scissor);
+    }
+
+    pub fn insert_debug_label(&self, name: &str) {
+        self.recorder.insert_debug_label(name);
+    }
+
+    pub fn draw(&mut self, vertex_count: u32, instance_count: u32, first_vertex: u32, first_instance: u32) {
+        unsafe {
+            self.recorder.device.raw.cmd_draw(
+                self.recorder.raw,
+                vertex_count,
+                instance_count,
+                first_vertex,
+                first_instance,
+            );
+        }
+    }
+
+    pub fn draw_indexed(
+        &mut self,
+        index_count: u32,
+        instance_count: u32,
+        first_index: u32,
+        vertex_offset: i32,
+        first_instance: u32,
+    ) {
+        unsafe {
+            self.recorder.device.raw.cmd_draw_indexed(
+                self.recorder.raw,
+                index_count,
+                instance_count,
+                first_index,
+                vertex_offset,
+                first_instance,
+            );
+        }
+    }
+}
+
+impl Drop for RenderPassScope<'_, '_> {
+    fn drop(&mut self) {
+        if self.ends_render_pass {
+            unsafe {
+                self.recorder.device.raw.cmd_end_render_pass(self.recorder.raw);
+            }
+        }
+    }
+}
+
+// a render pass of the primary that only executes secondary command buffers
+pub struct SecondaryPassScope<'r, 'a> {
+    recorder: &'r mut CommandRecorder<'a>,
+}
+
+impl<'a> SecondaryPassScope<'_, 'a> {
+    pub fn execute(&mut self, command_buffers: &[SecondaryCommandBuffer<'a>]) {
+        let raw = command_buffers.iter().map(|command_buffer| command_buffer.raw).collect::<Vec<_>>();
+        unsafe {
+            self.recorder.device.raw.cmd_execute_commands(self.recorder.raw, &raw);
+        }
+    }
+}
+
+impl Drop for SecondaryPassScope<'_, '_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.recorder.device.raw.cmd_end_render_pass(self.recorder.raw);
+        }
+        // executing secondaries leaves the primary's bindings undefined
+        self.recorder.state = Default::default();
+    }
+}
+
+// records part of a render pass, usually on a worker thread; `finish` it and hand the result to
+// `SecondaryPassScope::execute` before the frame is finished. It stays on the thread that began
+// it, whose pool the command buffer came from
+pub struct SecondaryRecorder<'a> {
+    recorder: CommandRecorder<'a>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a> SecondaryRecorder<'a> {
+    pub fn render_pass(&mut self) -> RenderPassScope<'_, 'a> {
+        RenderPassScope {
+            recorder: &mut self.recorder,
+            ends_render_pass: false,
+        }
+    }
+
+    // record the labelled commands through the returned scope
+    pub fn debug_label_scope(&mut self, name: &str) -> RecorderLabelScope<'_, 'a, Self> {
+        let label = self.recorder.device.debug_label_scope(self.recorder.raw, name);
+        RecorderLabelScope {
+            recorder: self,
+            _label: label,
+        }
+    }
+
+    pub fn finish(self) -> Result<SecondaryCommandBuffer<'a>> {
+        let raw = self.recorder.raw;
+        self.recorder.finish()?;
+
+        Ok(SecondaryCommandBuffer {
+            raw,
+            _frame: PhantomData,
+        })
+    }
+}
+
+// only valid until its frame retires, hence the borrow of the frame
+#[derive(Clone, Copy)]
+pub struct SecondaryCommandBuffer<'a> {
+    raw: vk::CommandBuffer,
+    _frame: PhantomData<&'a DeviceFrame>,
+}
+
+impl SecondaryCommandBuffer<'_> {
+    pub fn raw(&self) -> vk::CommandBuffer {
+        self.raw
+    }
+}
+
+// one per thread that records for a frame, so recording never contends on a pool; every buffer
+// is reset together with the pool once the frame's fence has signalled
+pub(crate) struct ThreadCommandPool {
+    pool: vk::CommandPool,
+    primaries: Vec<vk::CommandBuffer>,
+    secondaries: Vec<vk::CommandBuffer>,
+    used_primaries: usize,
+    used_secondaries: usize,
+}
+
+impl ThreadCommandPool {
+    fn create(device: &ash::Device, queue_family_index: u32) -> Result<ThreadCommandPool> {
+        let pool_create_info = vk::CommandPoolCreateInfo::builder()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(queue_family_index);
+
+        Ok(ThreadCommandPool {
+            pool: unsafe { device.create_command_pool(&pool_create_info, None)? },
+            primaries: Vec::new(),
+            secondaries: Vec::new(),
+            used_primaries: 0,
+            used_secondaries: 0,
+        })
+    }
+
+    fn next(&mut self, device: &ash::Device, level: vk::CommandBufferLevel) -> Result<vk::CommandBuffer> {
+        let (buffers, used) = if level == vk::CommandBufferLevel::PRIMARY {
+            (&mut self.primaries, &mut self.used_primaries)
+        } else {
+            (&mut self.secondaries, &mut self.used_secondaries)
+        };
+
+        if *used == buffers.len() {
+            let allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(self.pool)
+                .level(level)
+                .command_buffer_count(1);
+            buffers.extend(unsafe { device.allocate_command_buffers(&allocate_info)? });
+        }
+
+        let command_buffer = buffers[*used];
+        *used += 1;
+        Ok(command_buffer)
+    }
+
+    // nothing was allocated since the last reset
+    pub(crate) fn is_idle(&self) -> bool {
+        self.used_primaries + self.used_secondaries == 0
+    }
+
+    // the caller guarantees the GPU is done with every buffer from the pool
+    pub(crate) unsafe fn reset(&mut self, device: &ash::Device) -> Result<()> {
+        if !self.is_idle() {
+            device.reset_command_pool(self.pool, vk::CommandPoolResetFlags::empty())?;
+        }
+        self.used_primaries = 0;
+        self.used_secondaries = 0;
+        Ok(())
+    }
+
+    pub(crate) unsafe fn destroy(&mut self, device: &ash::Device) {
+        device.destroy_command_pool(self.pool, None);
+    }
+}
+
+pub(crate) type ThreadCommandPools = HashMap<ThreadId, ThreadCommandPool>;
+
+impl Device {
+    fn allocate_frame_command_buffer(&self, frame: &DeviceFrame, level: vk::CommandBufferLevel) -> Result<vk::CommandBuffer> {
+        let mut pools = frame.thread_command_pools.lock().unwrap();
+        let pool = match pools.entry(std::thread::current().id()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(ThreadCommandPool::create(&self.raw, self.queue_family_indices().graphics_family.unwrap())?)
+            }
+        };
+
+        pool.next(&self.raw, level)
+    }
+
+    // an extra primary from the calling thread's pool for `frame`, e.g. for `Device::submit`;
+    // it can't be reused once the frame has been begun again
+    pub fn begin_frame_command_buffer<'a>(&'a self, frame: &'a DeviceFrame) -> Result<CommandRecorder<'a>> {
+        let raw = self.allocate_frame_command_buffer(frame, vk::CommandBufferLevel::PRIMARY)?;
+
+        unsafe {
+            self.raw.begin_command_buffer(
+                raw,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+        }
+
+        Ok(CommandRecorder {
+            device: self,
+            raw,
+            owns_recording: true,
+            finished: false,
+            state: Default::default(),
+            _not_send: PhantomData,
+        })
+    }
+
+    // a secondary continuing `subpass` of `render_pass`; passing the framebuffer is optional
+    // but lets the driver optimize
+    pub fn begin_secondary<'a>(
+        &'a self,
+        frame: &'a DeviceFrame,
+        render_pass: &RenderPass,
+        subpass: u32,
+        framebuffer: Option<&Framebuffer>,
+    ) -> Result<SecondaryRecorder<'a>> {
+        let raw = self.allocate_frame_command_buffer(frame, vk::CommandBufferLevel::SECONDARY)?;
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(render_pass.raw)
+            .subpass(subpass)
+            .framebuffer(framebuffer.map_or_else(vk::Framebuffer::null, |framebuffer| framebuffer.raw));
+
+        unsafe {
+            self.raw.begin_command_buffer(
+                raw,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(
+                        vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT
+                            | vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE,
+                    )
+                    .inheritance_info(&inheritance_info),
+            )?;
+        }
+
+        Ok(SecondaryRecorder {
+            recorder: CommandRecorder {
+                device: self,
+                raw,
+                owns_recording: true,
+                finished: false,
+                state: Default::default(),
+                _not_send: PhantomData,
+            },
+            _not_send: PhantomData,
+        })
+    }
+}
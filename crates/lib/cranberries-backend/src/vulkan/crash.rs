@@ -0,0 +1,280 @@
+use anyhow::Result;
+use ash::vk;
+
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+
+use crate::vulkan::buffer::{Buffer, BufferDesc};
+use crate::vulkan::error::BackendError;
+use crate::vulkan::logical_device::Device;
+
+// names are kept for this many markers, older ids report as unknown
+const MAX_CRASH_MARKER_NAMES: usize = 4096;
+
+// the GPU only writes marker ids, their names and frames stay on the host
+#[derive(Default)]
+pub(crate) struct CrashMarkerNames {
+    next_id: u32,
+    // indexed by `id % MAX_CRASH_MARKER_NAMES`, grown on demand
+    markers: Vec<Option<(u32, CrashMarker)>>,
+}
+
+impl CrashMarkerNames {
+    fn insert(&mut self, marker: CrashMarker) -> u32 {
+        // 0 is what the buffer holds before any marker executed
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let id = self.next_id;
+
+        let slot = id as usize % MAX_CRASH_MARKER_NAMES;
+        if self.markers.len() <= slot {
+            self.markers.resize(slot + 1, None);
+        }
+        self.markers[slot] = Some((id, marker));
+        id
+    }
+
+    fn get(&self, id: u32) -> Option<&CrashMarker> {
+        match self.markers.get(id as usize % MAX_CRASH_MARKER_NAMES) {
+            Some(Some((marker_id, marker))) if *marker_id == id => Some(marker),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrashMarker {
+    pub name: String,
+    // `Device` frame counter when the marker was recorded
+    pub frame: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrashReport {
+    // only tracked with VK_AMD_buffer_marker
+    pub last_started: Option<CrashMarker>,
+    pub last_completed: Option<CrashMarker>,
+}
+
+impl std::fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.last_completed {
+            Some(marker) => write!(f, "last completed GPU marker: {:?} in frame {}", marker.name, marker.frame)?,
+            None => write!(f, "no GPU marker completed")?,
+        }
+        if let Some(marker) = &self.last_started {
+            write!(f, ", last started: {:?} in frame {}", marker.name, marker.frame)?;
+        }
+        std::result::Result::Ok(())
+    }
+}
+
+// breadcrumbs for `BackendError::DeviceLost`: a host-visible buffer with the id of the last
+// started and last completed marker
+pub(crate) struct CrashTracker {
+    buffer: Buffer,
+    // stays mapped, the memory is still readable after the device is lost
+    mapped: *const u32,
+    names: Mutex<CrashMarkerNames>,
+    // without it markers fall back to `cmd_fill_buffer` after a full barrier
+    buffer_marker: Option<vk::AmdBufferMarkerFn>,
+}
+
+// `mapped` is only read, and points into memory owned by `buffer`
+unsafe impl Send for CrashTracker {}
+unsafe impl Sync for CrashTracker {}
+
+const STARTED_OFFSET: u64 = 0;
+const COMPLETED_OFFSET: u64 = 4;
+
+impl CrashTracker {
+    pub(crate) fn create(device: &Device) -> Result<CrashTracker> {
+        let buffer = device.create_buffer(
+            BufferDesc {
+                size: 8,
+                usage: vk::BufferUsageFlags::TRANSFER_DST,
+                required_memory_flags: vk::MemoryPropertyFlags::HOST_VISIBLE
+                    | vk::MemoryPropertyFlags::HOST_COHERENT,
+                alignment: None,
+            },
+            Some("crash tracking buffer"),
+            None,
+        )?;
+
+        let mapped = unsafe {
+            let mapped = device
+                .raw
+                .map_memory(buffer.device_memory, 0, 8, vk::MemoryMapFlags::empty())?
+                as *mut u32;
+            mapped.write_bytes(0, 2);
+            mapped as *const u32
+        };
+
+        let buffer_marker = device.is_extension_enabled("VK_AMD_buffer_marker").then(|| {
+            vk::AmdBufferMarkerFn::load(|name| unsafe {
+                std::mem::transmute(
+                    device
+                        .instance
+                        .raw
+                        .get_device_proc_addr(device.raw.handle(), name.as_ptr()),
+                )
+            })
+        });
+
+        Ok(CrashTracker {
+            buffer,
+            mapped,
+            names: Default::default(),
+            buffer_marker,
+        })
+    }
+
+    pub(crate) fn destroy(self, device: &Device) {
+        unsafe { device.raw.unmap_memory(self.buffer.device_memory) };
+        device.immediate_destroy_buffer(self.buffer);
+    }
+}
+
+impl Device {
+    pub fn crash_tracking_enabled(&self) -> bool {
+        self.crash_tracker.is_some()
+    }
+
+    // does nothing unless crash tracking is enabled; without VK_AMD_buffer_marker this has to be
+    // recorded outside render passes
+    pub fn record_crash_marker(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        let tracker = match &self.crash_tracker {
+            Some(tracker) => tracker,
+            None => return,
+        };
+
+        let id = tracker.names.lock().unwrap().insert(CrashMarker {
+            name: name.to_owned(),
+            frame: self.frame_index.load(Ordering::Acquire),
+        });
+
+        unsafe {
+            if let Some(buffer_marker) = &tracker.buffer_marker {
+                buffer_marker.cmd_write_buffer_marker_amd(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    tracker.buffer.raw,
+                    STARTED_OFFSET,
+                    id,
+                );
+                buffer_marker.cmd_write_buffer_marker_amd(
+                    command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    tracker.buffer.raw,
+                    COMPLETED_OFFSET,
+                    id,
+                );
+            } else {
+                // the fill only lands once everything recorded before it is done
+                let barrier = vk::MemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+                self.raw.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[barrier.build()],
+                    &[],
+                    &[],
+                );
+                self.raw
+                    .cmd_fill_buffer(command_buffer, tracker.buffer.raw, COMPLETED_OFFSET, 4, id);
+            }
+        }
+    }
+
+    // what the GPU got through before it stopped; `None` without crash tracking
+    pub fn crash_report(&self) -> Option<CrashReport> {
+        let tracker = self.crash_tracker.as_ref()?;
+
+        let (started, completed) = unsafe {
+            (
+                tracker.mapped.add(STARTED_OFFSET as usize / 4).read_volatile(),
+                tracker.mapped.add(COMPLETED_OFFSET as usize / 4).read_volatile(),
+            )
+        };
+
+        let names = tracker.names.lock().unwrap();
+        Some(CrashReport {
+            last_started: names.get(started).cloned(),
+            last_completed: names.get(completed).cloned(),
+        })
+    }
+
+    // attaches the crash report to device lost errors, passes anything else through
+    pub(crate) fn report_device_lost(&self, err: impl Into<anyhow::Error>) -> anyhow::Error {
+        let err = err.into();
+        if !BackendError::of(&err).is_some_and(|err| err.is_device_lost()) {
+            return err;
+        }
+
+        match self.crash_report() {
+            Some(report) => err.context(report.to_string()),
+            None => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(name: &str) -> CrashMarker {
+        CrashMarker {
+            name: name.to_owned(),
+            frame: 0,
+        }
+    }
+
+    #[test]
+    fn marker_names_resolve_their_ids() {
+        let mut names = CrashMarkerNames::default();
+        assert_eq!(names.get(0), None);
+
+        let shadows = names.insert(marker("shadows"));
+        let main_pass = names.insert(marker("main pass"));
+        assert_eq!(shadows, 1);
+        assert_eq!(main_pass, 2);
+
+        assert_eq!(names.get(shadows), Some(&marker("shadows")));
+        assert_eq!(names.get(main_pass), Some(&marker("main pass")));
+        assert_eq!(names.get(0), None);
+        assert_eq!(names.get(3), None);
+    }
+
+    #[test]
+    fn old_marker_names_are_overwritten() {
+        let mut names = CrashMarkerNames::default();
+        let count = MAX_CRASH_MARKER_NAMES + 10;
+        for i in 0..count {
+            names.insert(marker(&format!("marker {}", i)));
+        }
+
+        // the first ten slots were reused by the newest markers
+        for id in 1..=10 {
+            assert_eq!(names.get(id), None);
+        }
+        let newest = count as u32;
+        assert_eq!(names.get(newest), Some(&marker(&format!("marker {}", count - 1))));
+        let oldest_kept = newest - MAX_CRASH_MARKER_NAMES as u32 + 1;
+        assert_eq!(names.get(oldest_kept), Some(&marker(&format!("marker {}", oldest_kept - 1))));
+    }
+
+    #[test]
+    fn marker_ids_skip_zero_when_wrapping() {
+        let mut names = CrashMarkerNames {
+            next_id: u32::MAX,
+            ..Default::default()
+        };
+
+        let id = names.insert(marker("wrapped"));
+        assert_eq!(id, 1);
+        assert_eq!(names.get(1), Some(&marker("wrapped")));
+        assert_eq!(names.get(0), None);
+    }
+}
@@ -0,0 +1,102 @@
+use ash::vk;
+
+use std::path::PathBuf;
+
+// the failures an app can sensibly react to; backend functions return them inside
+// `anyhow::Error`, use `BackendError::of` to get them back out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendError {
+    OutOfHostMemory,
+    OutOfDeviceMemory,
+    DeviceLost,
+    SurfaceLost,
+    // the swapchain has to be recreated
+    OutOfDate,
+    NoSuitableDevice { reasons: Vec<String> },
+    NoSuitableMemoryType { type_bits: u32, flags: vk::MemoryPropertyFlags },
+    UnsupportedFormat { format: vk::Format, features: vk::FormatFeatureFlags },
+    UnsupportedFeature(String),
+    UnsupportedLayoutTransition { old: vk::ImageLayout, new: vk::ImageLayout },
+    InvalidSpirv(String),
+    // the caller passed something the backend can't use, e.g. an out of bounds range
+    InvalidArgument(String),
+    MissingFile { path: PathBuf, kind: std::io::ErrorKind },
+    // `begin_frame` / `finish_frame` while user code still holds the frame's `Arc`
+    FrameInUse,
+    Vulkan(vk::Result),
+}
+
+impl BackendError {
+    // the first `BackendError` or `vk::Result` in the chain
+    pub fn of(err: &anyhow::Error) -> Option<BackendError> {
+        err.chain().find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<BackendError>() {
+                Some(err.clone())
+            } else {
+                cause.downcast_ref::<vk::Result>().map(|&result| BackendError::from(result))
+            }
+        })
+    }
+
+    // the device has to be recreated, nothing on it can be trusted any more
+    pub fn is_device_lost(&self) -> bool {
+        *self == BackendError::DeviceLost
+    }
+
+    // recreating the swapchain (and maybe the surface) recovers from these
+    pub fn needs_swapchain_recreation(&self) -> bool {
+        matches!(self, BackendError::OutOfDate | BackendError::SurfaceLost)
+    }
+}
+
+impl From<vk::Result> for BackendError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY => BackendError::OutOfHostMemory,
+            vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => BackendError::OutOfDeviceMemory,
+            vk::Result::ERROR_DEVICE_LOST => BackendError::DeviceLost,
+            vk::Result::ERROR_SURFACE_LOST_KHR => BackendError::SurfaceLost,
+            vk::Result::ERROR_OUT_OF_DATE_KHR => BackendError::OutOfDate,
+            vk::Result::ERROR_INVALID_SHADER_NV => BackendError::InvalidSpirv("rejected by the driver".to_owned()),
+            other => BackendError::Vulkan(other),
+        }
+    }
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::OutOfHostMemory => write!(f, "Out of host memory"),
+            BackendError::OutOfDeviceMemory => write!(f, "Out of device memory"),
+            BackendError::DeviceLost => write!(f, "Device lost"),
+            BackendError::SurfaceLost => write!(f, "Surface lost"),
+            BackendError::OutOfDate => write!(f, "Swapchain is out of date"),
+            BackendError::NoSuitableDevice { reasons } => {
+                write!(f, "Failed to find a suitable GPU!")?;
+                if !reasons.is_empty() {
+                    write!(f, " Rejected devices:\n{}", reasons.join("\n"))?;
+                }
+                Ok(())
+            }
+            BackendError::NoSuitableMemoryType { type_bits, flags } => write!(
+                f,
+                "Failed to find suitable memory type for bits {:#b} with {:?}",
+                type_bits, flags
+            ),
+            BackendError::UnsupportedFormat { format, features } => {
+                write!(f, "Format {:?} doesn't support {:?}", format, features)
+            }
+            BackendError::UnsupportedFeature(feature) => write!(f, "Unsupported feature: {}", feature),
+            BackendError::UnsupportedLayoutTransition { old, new } => {
+                write!(f, "Unsupported layout transition {:?} -> {:?}", old, new)
+            }
+            BackendError::InvalidSpirv(reason) => write!(f, "Invalid SPIR-V: {}", reason),
+            BackendError::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+            BackendError::MissingFile { path, kind } => write!(f, "Failed to read {:?}: {:?}", path, kind),
+            BackendError::FrameInUse => write!(f, "Frame data is being held by user code"),
+            BackendError::Vulkan(result) => write!(f, "Vulkan error: {}", result),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
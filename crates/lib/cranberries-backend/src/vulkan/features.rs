@@ -0,0 +1,347 @@
+use anyhow::Result;
+use ash::vk;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::vulkan::error::BackendError;
+use crate::vulkan::physical_device::DeviceFeatures;
+
+// features the backend knows how to query and enable; groups like descriptor indexing
+// switch on the handful of bits bindless rendering needs together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceFeature {
+    // core 1.0
+    SamplerAnisotropy,
+    FillModeNonSolid,
+    WideLines,
+    DepthClamp,
+    GeometryShader,
+    TessellationShader,
+    MultiDrawIndirect,
+    IndependentBlend,
+    PipelineStatisticsQuery,
+    TextureCompressionBc,
+    ShaderInt64,
+    ShaderFloat64,
+    // 1.1
+    Multiview,
+    ShaderDrawParameters,
+    // 1.2
+    DescriptorIndexing,
+    TimelineSemaphore,
+    BufferDeviceAddress,
+    ScalarBlockLayout,
+    DrawIndirectCount,
+    ShaderFloat16,
+    // extensions
+    Synchronization2,
+    DynamicRendering,
+}
+
+impl DeviceFeature {
+    pub const ALL: [DeviceFeature; 22] = [
+        DeviceFeature::SamplerAnisotropy,
+        DeviceFeature::FillModeNonSolid,
+        DeviceFeature::WideLines,
+        DeviceFeature::DepthClamp,
+        DeviceFeature::GeometryShader,
+        DeviceFeature::TessellationShader,
+        DeviceFeature::MultiDrawIndirect,
+        DeviceFeature::IndependentBlend,
+        DeviceFeature::PipelineStatisticsQuery,
+        DeviceFeature::TextureCompressionBc,
+        DeviceFeature::ShaderInt64,
+        DeviceFeature::ShaderFloat64,
+        DeviceFeature::Multiview,
+        DeviceFeature::ShaderDrawParameters,
+        DeviceFeature::DescriptorIndexing,
+        DeviceFeature::TimelineSemaphore,
+        DeviceFeature::BufferDeviceAddress,
+        DeviceFeature::ScalarBlockLayout,
+        DeviceFeature::DrawIndirectCount,
+        DeviceFeature::ShaderFloat16,
+        DeviceFeature::Synchronization2,
+        DeviceFeature::DynamicRendering,
+    ];
+
+    // the device extension that has to be enabled alongside the feature
+    pub fn extension(self) -> Option<&'static CStr> {
+        match self {
+            DeviceFeature::Synchronization2 => Some(vk::KhrSynchronization2Fn::name()),
+            DeviceFeature::DynamicRendering => Some(DYNAMIC_RENDERING_EXTENSION_NAME),
+            _ => None,
+        }
+    }
+
+    pub fn is_supported(self, features: &DeviceFeatures) -> bool {
+        let mut features = *features;
+        self.bits(&mut features).into_iter().all(|bit| *bit == vk::TRUE)
+    }
+
+    pub(crate) fn enable(self, features: &mut DeviceFeatures) {
+        for bit in self.bits(features) {
+            *bit = vk::TRUE;
+        }
+    }
+
+    fn bits(self, features: &mut DeviceFeatures) -> Vec<&mut vk::Bool32> {
+        let DeviceFeatures {
+            core,
+            vulkan_11,
+            vulkan_12,
+            synchronization2,
+            dynamic_rendering,
+        } = features;
+
+        match self {
+            DeviceFeature::SamplerAnisotropy => vec![&mut core.sampler_anisotropy],
+            DeviceFeature::FillModeNonSolid => vec![&mut core.fill_mode_non_solid],
+            DeviceFeature::WideLines => vec![&mut core.wide_lines],
+            DeviceFeature::DepthClamp => vec![&mut core.depth_clamp],
+            DeviceFeature::GeometryShader => vec![&mut core.geometry_shader],
+            DeviceFeature::TessellationShader => vec![&mut core.tessellation_shader],
+            DeviceFeature::MultiDrawIndirect => vec![&mut core.multi_draw_indirect],
+            DeviceFeature::IndependentBlend => vec![&mut core.independent_blend],
+            DeviceFeature::PipelineStatisticsQuery => vec![&mut core.pipeline_statistics_query],
+            DeviceFeature::TextureCompressionBc => vec![&mut core.texture_compression_bc],
+            DeviceFeature::ShaderInt64 => vec![&mut core.shader_int64],
+            DeviceFeature::ShaderFloat64 => vec![&mut core.shader_float64],
+            DeviceFeature::Multiview => vec![&mut vulkan_11.multiview],
+            DeviceFeature::ShaderDrawParameters => vec![&mut vulkan_11.shader_draw_parameters],
+            DeviceFeature::DescriptorIndexing => vec![
+                &mut vulkan_12.descriptor_indexing,
+                &mut vulkan_12.runtime_descriptor_array,
+                &mut vulkan_12.descriptor_binding_partially_bound,
+                &mut vulkan_12.descriptor_binding_variable_descriptor_count,
+                &mut vulkan_12.descriptor_binding_update_unused_while_pending,
+                &mut vulkan_12.descriptor_binding_sampled_image_update_after_bind,
+                &mut vulkan_12.shader_sampled_image_array_non_uniform_indexing,
+            ],
+            DeviceFeature::TimelineSemaphore => vec![&mut vulkan_12.timeline_semaphore],
+            DeviceFeature::BufferDeviceAddress => vec![&mut vulkan_12.buffer_device_address],
+            DeviceFeature::ScalarBlockLayout => vec![&mut vulkan_12.scalar_block_layout],
+            DeviceFeature::DrawIndirectCount => vec![&mut vulkan_12.draw_indirect_count],
+            DeviceFeature::ShaderFloat16 => vec![&mut vulkan_12.shader_float16],
+            DeviceFeature::Synchronization2 => vec![&mut synchronization2.synchronization2],
+            DeviceFeature::DynamicRendering => vec![&mut dynamic_rendering.dynamic_rendering],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceFeatureSet(u64);
+
+impl DeviceFeatureSet {
+    pub fn empty() -> Self {
+        DeviceFeatureSet(0)
+    }
+
+    pub fn insert(&mut self, feature: DeviceFeature) {
+        self.0 |= 1 << feature as u64;
+    }
+
+    pub fn contains(&self, feature: DeviceFeature) -> bool {
+        self.0 & (1 << feature as u64) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = DeviceFeature> + '_ {
+        DeviceFeature::ALL
+            .iter()
+            .copied()
+            .filter(move |&feature| self.contains(feature))
+    }
+}
+
+impl std::fmt::Debug for DeviceFeatureSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+// what `Device::create` should switch on: missing required features reject the device,
+// missing optional ones are skipped with a warning
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceFeatureRequest {
+    required: DeviceFeatureSet,
+    optional: DeviceFeatureSet,
+}
+
+impl Default for DeviceFeatureRequest {
+    fn default() -> Self {
+        DeviceFeatureRequest::new()
+            .optional(DeviceFeature::SamplerAnisotropy)
+            // `Device::submit` and `GpuFuture` need it
+            .optional(DeviceFeature::TimelineSemaphore)
+    }
+}
+
+impl DeviceFeatureRequest {
+    // nothing at all, unlike `default()`
+    pub fn new() -> Self {
+        DeviceFeatureRequest {
+            required: DeviceFeatureSet::empty(),
+            optional: DeviceFeatureSet::empty(),
+        }
+    }
+
+    pub fn require(mut self, feature: DeviceFeature) -> Self {
+        self.required.insert(feature);
+        self
+    }
+
+    pub fn optional(mut self, feature: DeviceFeature) -> Self {
+        self.optional.insert(feature);
+        self
+    }
+
+    pub fn required_features(&self) -> DeviceFeatureSet {
+        self.required
+    }
+
+    pub fn optional_features(&self) -> DeviceFeatureSet {
+        self.optional
+    }
+
+    pub fn missing_required(&self, supported: &DeviceFeatures) -> Vec<DeviceFeature> {
+        self.required
+            .iter()
+            .filter(|feature| !feature.is_supported(supported))
+            .collect()
+    }
+
+    // the features to enable on a device reporting `supported`
+    pub fn resolve(&self, supported: &DeviceFeatures) -> Result<DeviceFeatureSet> {
+        let missing = self.missing_required(supported);
+        if !missing.is_empty() {
+            anyhow::bail!(BackendError::UnsupportedFeature(format!(
+                "missing required device features: {:?}",
+                missing
+            )));
+        }
+
+        let mut enabled = self.required;
+        for feature in self.optional.iter() {
+            if feature.is_supported(supported) {
+                enabled.insert(feature);
+            } else {
+                log::warn!("Optional device feature {:?} is not supported, skipping", feature);
+            }
+        }
+
+        Ok(enabled)
+    }
+}
+
+// VK_KHR_dynamic_rendering is newer than the headers ash 0.33 was generated from
+pub(crate) const DYNAMIC_RENDERING_EXTENSION_NAME: &CStr = c"VK_KHR_dynamic_rendering";
+
+const STRUCTURE_TYPE_PHYSICAL_DEVICE_DYNAMIC_RENDERING_FEATURES_KHR: vk::StructureType =
+    vk::StructureType::from_raw(1_000_044_003);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PhysicalDeviceDynamicRenderingFeaturesKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub dynamic_rendering: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceDynamicRenderingFeaturesKHR {
+    fn default() -> Self {
+        PhysicalDeviceDynamicRenderingFeaturesKHR {
+            s_type: STRUCTURE_TYPE_PHYSICAL_DEVICE_DYNAMIC_RENDERING_FEATURES_KHR,
+            p_next: std::ptr::null_mut(),
+            dynamic_rendering: vk::FALSE,
+        }
+    }
+}
+
+unsafe impl vk::ExtendsPhysicalDeviceFeatures2 for PhysicalDeviceDynamicRenderingFeaturesKHR {}
+unsafe impl vk::ExtendsDeviceCreateInfo for PhysicalDeviceDynamicRenderingFeaturesKHR {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_set_insert_and_iter() {
+        let mut set = DeviceFeatureSet::empty();
+        assert!(set.is_empty());
+
+        set.insert(DeviceFeature::DynamicRendering);
+        set.insert(DeviceFeature::SamplerAnisotropy);
+        set.insert(DeviceFeature::SamplerAnisotropy);
+
+        assert!(!set.is_empty());
+        assert!(set.contains(DeviceFeature::SamplerAnisotropy));
+        assert!(!set.contains(DeviceFeature::TimelineSemaphore));
+        // in `DeviceFeature::ALL` order, whatever the insertion order
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            vec![DeviceFeature::SamplerAnisotropy, DeviceFeature::DynamicRendering]
+        );
+    }
+
+    #[test]
+    fn every_feature_has_its_own_bit() {
+        let mut set = DeviceFeatureSet::empty();
+        for feature in DeviceFeature::ALL {
+            assert!(!set.contains(feature));
+            set.insert(feature);
+        }
+        assert_eq!(set.iter().count(), DeviceFeature::ALL.len());
+    }
+
+    #[test]
+    fn group_features_need_every_bit() {
+        let mut supported = DeviceFeatures::default();
+        supported.vulkan_12.descriptor_indexing = vk::TRUE;
+        assert!(!DeviceFeature::DescriptorIndexing.is_supported(&supported));
+
+        DeviceFeature::DescriptorIndexing.enable(&mut supported);
+        assert!(DeviceFeature::DescriptorIndexing.is_supported(&supported));
+    }
+
+    #[test]
+    fn resolve_enables_supported_optional_features() {
+        let mut supported = DeviceFeatures::default();
+        DeviceFeature::SamplerAnisotropy.enable(&mut supported);
+        DeviceFeature::TimelineSemaphore.enable(&mut supported);
+
+        let request = DeviceFeatureRequest::new()
+            .require(DeviceFeature::TimelineSemaphore)
+            .optional(DeviceFeature::SamplerAnisotropy)
+            .optional(DeviceFeature::GeometryShader);
+
+        assert!(request.missing_required(&supported).is_empty());
+
+        let enabled = request.resolve(&supported).unwrap();
+        assert_eq!(
+            enabled.iter().collect::<Vec<_>>(),
+            vec![DeviceFeature::SamplerAnisotropy, DeviceFeature::TimelineSemaphore]
+        );
+    }
+
+    #[test]
+    fn resolve_fails_on_missing_required_features() {
+        let mut supported = DeviceFeatures::default();
+        DeviceFeature::SamplerAnisotropy.enable(&mut supported);
+
+        let request = DeviceFeatureRequest::new()
+            .require(DeviceFeature::SamplerAnisotropy)
+            .require(DeviceFeature::BufferDeviceAddress)
+            .require(DeviceFeature::Synchronization2);
+
+        assert_eq!(
+            request.missing_required(&supported),
+            vec![DeviceFeature::BufferDeviceAddress, DeviceFeature::Synchronization2]
+        );
+        let err = request.resolve(&supported).unwrap_err();
+        assert!(matches!(BackendError::of(&err), Some(BackendError::UnsupportedFeature(_))));
+    }
+}
@@ -1,12 +1,11 @@
 use anyhow::Ok;
 use ash::vk;
-use ash::vk::Extent2D;
 
-use crate::vulkan::logical_device::Device;
-use crate::vulkan::swapchain::SwapchainImage;
-use crate::vulkan::buffer::{Buffer, BufferDesc};
+use crate::vulkan::logical_device::{Device, ReleaseQueue};
+use crate::vulkan::features::DeviceFeature;
+use crate::vulkan::timeline::QueueType;
+use crate::vulkan::error::BackendError;
 
-use std::default;
 use std::sync::Arc;
 use std::sync::Mutex;
 use anyhow::Result;
@@ -47,6 +46,7 @@ pub struct Image {
     //allocation: gpu_allocator::SubAllocation,
     pub device: ash::Device,
     pub device_memory: vk::DeviceMemory,
+    pub(crate) release_queue: Arc<ReleaseQueue>,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
@@ -58,14 +58,14 @@ pub struct ImageViewDesc {
     pub level_count: Option<u32>,
 }
 
-impl ImageViewDesc {
-    pub fn default() -> ImageViewDesc{
+impl Default for ImageViewDesc {
+    fn default() -> ImageViewDesc{
 
         ImageViewDesc {
             view_type: Some(vk::ImageViewType::default()),
             format: Some(vk::Format::default()),
             aspect_mask: vk::ImageAspectFlags::COLOR,
-            base_mip_level: 0 as u32,
+            base_mip_level: 0,
             level_count: Some(1),
         }
     }
@@ -91,7 +91,7 @@ impl Image {
         if let Some(entry) = views.get(desc) {
             Ok(*entry)
         } else {
-            let view = device.create_image_view(*desc, &self.desc, self.raw)?;
+            let view = device.create_image_view(*desc, &self.desc, self.raw, None)?;
             Ok(*views.entry(*desc).or_insert(view))
         }
     }
@@ -112,7 +112,15 @@ impl Image {
                 b: vk::ComponentSwizzle::IDENTITY,
                 a: vk::ComponentSwizzle::IDENTITY,
             },
-            view_type: desc.view_type.unwrap(),//
+            view_type: desc.view_type.unwrap_or(match image_desc.image_type {
+                ImageType::Tex1d => vk::ImageViewType::TYPE_1D,
+                ImageType::Tex1dArray => vk::ImageViewType::TYPE_1D_ARRAY,
+                ImageType::Tex2d => vk::ImageViewType::TYPE_2D,
+                ImageType::Tex2dArray => vk::ImageViewType::TYPE_2D_ARRAY,
+                ImageType::Tex3d => vk::ImageViewType::TYPE_3D,
+                ImageType::Cube => vk::ImageViewType::CUBE,
+                ImageType::CubeArray => vk::ImageViewType::CUBE_ARRAY,
+            }),
             subresource_range: vk::ImageSubresourceRange {
                 aspect_mask: desc.aspect_mask,
                 base_mip_level: desc.base_mip_level,
@@ -133,10 +141,18 @@ impl Image {
 
 impl Drop for Image {
     fn drop(&mut self) {
-        unsafe {
-            self.device.destroy_image(self.raw, None);
-            self.device.free_memory(self.device_memory, None);
-        }
+        let views = std::mem::take(self.views.get_mut().unwrap());
+        // swapchain images have no memory of ours and are destroyed with the swapchain
+        let owned = self.device_memory != vk::DeviceMemory::null();
+        let (raw, device_memory) = (self.raw, self.device_memory);
+
+        self.release_queue.defer(|pending| {
+            pending.image_views.extend(views.into_values());
+            if owned {
+                pending.images.push(raw);
+                pending.memory.push(device_memory);
+            }
+        });
     }
 }
 
@@ -146,10 +162,13 @@ impl Device {
         desc: ImageViewDesc,
         image_desc: &ImageDesc,
         image_raw: vk::Image,
+        name: Option<&str>,
     ) -> Result<vk::ImageView> {
-        if image_desc.format == vk::Format::D32_SFLOAT
+        if is_depth_only_format(image_desc.format)
             && !desc.aspect_mask.contains(vk::ImageAspectFlags::DEPTH) {
-                anyhow::bail!("Depth-only resource used without the vk::ImageAspectFlags::DEPTH flag");
+                anyhow::bail!(BackendError::InvalidArgument(
+                    "depth-only resource used without the vk::ImageAspectFlags::DEPTH flag".to_owned()
+                ));
             }
         
         let create_info = vk::ImageViewCreateInfo {
@@ -159,44 +178,133 @@ impl Device {
 
         //println!("image view {:#?}", create_info);
 
-        Ok(
-            unsafe {
-                self.raw.create_image_view(&create_info, None)
-                .expect("Failded to create Image View!")
-            }
-        )
+        let image_view = unsafe { self.raw.create_image_view(&create_info, None)? };
+
+        if let Some(name) = name {
+            self.set_debug_name(image_view, name);
+        }
+
+        Ok(image_view)
     }
 
     pub fn create_image_views(
         &self,
         surface_format: vk::Format,
-        images: &Vec<vk::Image>,
+        images: &[vk::Image],
         //images: &Vec<SwapchainImage>,
         extent: vk::Extent2D,
-    ) -> Vec<vk::ImageView> {
+    ) -> Result<Vec<vk::ImageView>> {
         let image_view_desc = ImageViewDesc {
             view_type: Some(vk::ImageViewType::TYPE_2D),
             format: Some(surface_format),
             aspect_mask: vk::ImageAspectFlags::COLOR,
-            base_mip_level: 0 as u32,
-            level_count: Some(1 as u32),
+            base_mip_level: 0,
+            level_count: Some(1),
         };
 
         let image_desc = ImageDesc::create(surface_format, ImageType::Tex2d, [extent.width, extent.height,0]);
 
-        let image_views :Vec<vk::ImageView> = images
+        images
             .iter()
-            .map(|image| {
+            .enumerate()
+            .map(|(i, image)| {
                 self.create_image_view(
                     image_view_desc,
                     &image_desc,
                     *image,
-                ).unwrap()
+                    Some(&format!("swapchain image view {}", i)),
+                )
             })
-            .collect();
+            .collect()
+    }
+}
+
+pub fn is_depth_only_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT
+    )
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::S8_UINT
+            | vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
 
-        image_views
+// bytes per texel of uncompressed color formats, `None` for anything else
+pub fn color_texel_size(format: vk::Format) -> Option<u64> {
+    let size = match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SNORM | vk::Format::R8_UINT | vk::Format::R8_SINT | vk::Format::R8_SRGB => 1,
+        vk::Format::R8G8_UNORM
+        | vk::Format::R8G8_SNORM
+        | vk::Format::R8G8_UINT
+        | vk::Format::R8G8_SINT
+        | vk::Format::R16_UNORM
+        | vk::Format::R16_UINT
+        | vk::Format::R16_SINT
+        | vk::Format::R16_SFLOAT => 2,
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SNORM
+        | vk::Format::R8G8B8A8_UINT
+        | vk::Format::R8G8B8A8_SINT
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::R16G16_UNORM
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R32_UINT
+        | vk::Format::R32_SINT
+        | vk::Format::R32_SFLOAT => 4,
+        vk::Format::R16G16B16A16_UNORM
+        | vk::Format::R16G16B16A16_UINT
+        | vk::Format::R16G16B16A16_SFLOAT
+        | vk::Format::R32G32_UINT
+        | vk::Format::R32G32_SFLOAT => 8,
+        vk::Format::R32G32B32A32_UINT | vk::Format::R32G32B32A32_SINT | vk::Format::R32G32B32A32_SFLOAT => 16,
+        _ => return None,
+    };
+    Some(size)
+}
+
+// format features an image needs for `usage`; transfer bits are left out since 1.0 drivers don't report them
+pub fn format_features_for_usage(usage: vk::ImageUsageFlags) -> vk::FormatFeatureFlags {
+    let mut features = vk::FormatFeatureFlags::empty();
+    if usage.contains(vk::ImageUsageFlags::SAMPLED) {
+        features |= vk::FormatFeatureFlags::SAMPLED_IMAGE;
+    }
+    if usage.contains(vk::ImageUsageFlags::STORAGE) {
+        features |= vk::FormatFeatureFlags::STORAGE_IMAGE;
+    }
+    if usage.contains(vk::ImageUsageFlags::COLOR_ATTACHMENT) {
+        features |= vk::FormatFeatureFlags::COLOR_ATTACHMENT;
     }
+    if usage.contains(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT) {
+        features |= vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT;
+    }
+    features
+}
+
+// decoded to RGBA8 and flipped vertically, as width, height and texels
+pub fn load_rgba8(image_path: &Path) -> Result<(u32, u32, Vec<u8>)> {
+    let image_object = image::open(image_path)
+        .map_err(|err| anyhow::anyhow!("Failed to load texture image {:?}: {}", image_path, err))?; // this function is slow in debug mode.
+    let image_object = image_object.flipv();
+    let (image_width, image_height) = (image_object.width(), image_object.height());
+    // every layout is converted, even BGRA8 would come out with red and blue swapped
+    let image_data = image_object.to_rgba().into_raw();
+
+    if image_width == 0 || image_height == 0 {
+        anyhow::bail!("Failed to load texture image {:?}: it is empty", image_path);
+    }
+
+    Ok((image_width, image_height, image_data))
 }
 
 impl ImageDesc {
@@ -231,41 +339,62 @@ impl Device {
         desc: ImageDesc,
         //initial_data: Vec<ImageSubResourceData>,
         required_memory_properties: vk::MemoryPropertyFlags,
-        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        name: Option<&str>,
     ) -> Result<Image> {
+        let required_features = format_features_for_usage(desc.usage);
+        if !self.pdevice.supports_format(desc.format, desc.tiling, required_features) {
+            return Err(anyhow::Error::new(BackendError::UnsupportedFormat {
+                format: desc.format,
+                features: required_features,
+            })
+            .context(format!("Creating a {:?} tiled image", desc.tiling)));
+        }
+
         let create_info = get_image_create_info(&desc, false);
 
         //println!("image create info {:#?}",  create_info);
 
-        let image = unsafe {
-            self.raw
-                .create_image(&create_info, None)
-                .expect("create_image")
-        };
+        let image = unsafe { self.raw.create_image(&create_info, None)? };
 
         let requirements = unsafe { self.raw.get_image_memory_requirements(image) };
 
+        let memory_type_index = match find_memory_type(
+            requirements.memory_type_bits,
+            required_memory_properties,
+            self.pdevice.memory_properties(),
+        ) {
+            std::result::Result::Ok(index) => index,
+            Err(err) => {
+                unsafe { self.raw.destroy_image(image, None) };
+                return Err(err.into());
+            }
+        };
+
         let memory_allocate_info = vk::MemoryAllocateInfo {
             s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
             p_next: ptr::null(),
             allocation_size: requirements.size,
-            memory_type_index: find_memory_type(
-                requirements.memory_type_bits,
-                required_memory_properties,
-                device_memory_properties,
-            ),
+            memory_type_index,
         };
 
         let image_memory = unsafe {
             self.raw
                 .allocate_memory(&memory_allocate_info, None)
-                .expect("Failed to allocate Texture Image memory!")
+                .inspect_err(|_| self.raw.destroy_image(image, None))?
         };
 
         unsafe {
             self.raw
                 .bind_image_memory(image, image_memory, 0)
-                .expect("Failed to bind Image Memmory!");
+                .inspect_err(|_| {
+                    self.raw.destroy_image(image, None);
+                    self.raw.free_memory(image_memory, None);
+                })?;
+        }
+
+        if let Some(name) = name {
+            self.set_debug_name(image, name);
+            self.set_debug_name(image_memory, name);
         }
 
         Ok(Image {
@@ -275,15 +404,43 @@ impl Device {
             views: Default::default(),
             device: self.raw.clone(),
             device_memory: image_memory,
+            release_queue: self.release_queue.clone(),
         })
     }
 
+    // like `immediate_destroy_buffer`, for images the GPU is known to be done with
+    pub fn immediate_destroy_image(&self, mut image: Image) {
+        unsafe {
+            for (_, view) in image.views.get_mut().unwrap().drain() {
+                self.raw.destroy_image_view(view, None);
+            }
+            if image.device_memory != vk::DeviceMemory::null() {
+                self.raw.destroy_image(image.raw, None);
+                self.raw.free_memory(image.device_memory, None);
+            }
+        }
+        image.device_memory = vk::DeviceMemory::null();
+    }
+
+    // color target for headless rendering, copyable back to the host
+    pub fn create_offscreen_image(
+        &self,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        name: Option<&str>,
+    ) -> Result<Image> {
+        let mut desc = ImageDesc::create(format, ImageType::Tex2d, [extent.width, extent.height, 1]);
+        desc.usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC;
+
+        self.create_image(desc, vk::MemoryPropertyFlags::DEVICE_LOCAL, name)
+    }
+
     pub fn transition_image_layout(
         &self,
         image: &Image,
         old_layout: vk::ImageLayout,
         new_layout: vk::ImageLayout,
-    ) {
+    ) -> Result<()> {
         let src_access_mask;
         let dst_access_mask;
         let source_stage;
@@ -304,7 +461,11 @@ impl Device {
             source_stage = vk::PipelineStageFlags::TRANSFER;
             destination_stage = vk::PipelineStageFlags::FRAGMENT_SHADER;
         } else {
-            panic!("Unsupported layout transition!")
+            return Err(BackendError::UnsupportedLayoutTransition {
+                old: old_layout,
+                new: new_layout,
+            }
+            .into());
         }
 
         let image_barriers = [vk::ImageMemoryBarrier {
@@ -326,121 +487,27 @@ impl Device {
             },
         }];
 
-        self.with_setup_cb(|cb|
-            unsafe {
-                self.raw
-                    .cmd_pipeline_barrier(
-                        cb,
-                        source_stage,
-                        destination_stage, 
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[], 
-                        &image_barriers);
-        }).unwrap();
+        self.with_setup_cb(|recorder| {
+            recorder.pipeline_barrier(source_stage, destination_stage, &[], &image_barriers);
+        })
     }
 
+    // one-off upload; use `begin_upload` to batch several textures into one submission
     pub fn create_texture_image(
         &self,
-        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
         image_path: &Path,
     ) -> Result<Image> {
-        //println!("path: {}", image_path);
-        let mut image_object = image::open(image_path).unwrap(); // this function is slow in debug mode.
-        image_object = image_object.flipv();
-        let (image_width, image_height) = (image_object.width(), image_object.height());
-        let image_size =
-            (std::mem::size_of::<u8>() as u32 * image_width * image_height * 4) as vk::DeviceSize;
-        let image_data = match &image_object {
-            image::DynamicImage::ImageLuma8(_)
-            | image::DynamicImage::ImageBgr8(_)
-            | image::DynamicImage::ImageRgb8(_) => image_object.to_rgba().into_raw(),
-            image::DynamicImage::ImageLumaA8(_)
-            | image::DynamicImage::ImageBgra8(_)
-            | image::DynamicImage::ImageRgba8(_) => image_object.raw_pixels(),
-        };
-
-        if image_size <= 0 {
-            panic!("Failed to load texture image!")
-        }
-
-        let staging_buffer_desc = BufferDesc {
-            size: image_size as usize,
-            usage: vk::BufferUsageFlags::TRANSFER_SRC,
-            required_memory_flags: vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-            alignment: None,
-        };
-
-        let mut staging_buffer = self.create_buffer(staging_buffer_desc, None).unwrap();
-        
-        unsafe {
-            let data_ptr = self.raw
-                .map_memory(
-                    staging_buffer.device_memory,
-                    0,
-                    image_size,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .expect("Failed to Map Memory") as *mut u8;
-
-            data_ptr.copy_from_nonoverlapping(image_data.as_ptr(), image_data.len());
-
-            self.raw.unmap_memory(staging_buffer.device_memory);
-        }
-
-        let texture_image_desc = ImageDesc {
-            image_type: ImageType::Tex2d,
-            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
-            flags: vk::ImageCreateFlags::empty(),
-            format: vk::Format::R8G8B8A8_SRGB,
-            extent: [image_width, image_height, 1],
-            tiling: vk::ImageTiling::OPTIMAL,
-            mip_levels: 1,
-            array_elements: 1,
-        };
-
-        let mut texture_image = self.create_image(texture_image_desc, vk::MemoryPropertyFlags::DEVICE_LOCAL, device_memory_properties).unwrap();
-
-        self.transition_image_layout(&texture_image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
-
-        self.with_setup_cb(|cb| {
-            let buffer_image_regions = [vk::BufferImageCopy {
-                image_subresource: vk::ImageSubresourceLayers {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    mip_level: 0,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                },
-                image_extent: vk::Extent3D {
-                    width: image_width,
-                    height: image_height,
-                    depth: 1,
-                },
-                buffer_offset: 0,
-                buffer_image_height: 0,
-                buffer_row_length: 0,
-                image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
-            }];
-
-            unsafe {
-                self.raw.cmd_copy_buffer_to_image(
-                    cb,
-                    staging_buffer.raw,
-                    texture_image.raw,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &buffer_image_regions,
-                );
-            }
-        }).unwrap();
-
-        self.transition_image_layout(&texture_image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
-        
-        self.immediate_destroy_buffer(&mut staging_buffer);
+        let mut batch = self.begin_upload(QueueType::Transfer)?;
+        let texture_image = batch.create_texture_image(image_path)?;
+        batch.submit()?.wait()?;
 
         Ok(texture_image)
     }
 
     pub fn create_texture_sampler(&self) -> Result<vk::Sampler> {
+        // anisotropy is only requested optionally, so fall back to plain trilinear filtering
+        let anisotropy = self.is_feature_enabled(DeviceFeature::SamplerAnisotropy);
+
         let sampler_create_info = vk::SamplerCreateInfo {
             s_type: vk::StructureType::SAMPLER_CREATE_INFO,
             p_next: ptr::null(),
@@ -452,8 +519,12 @@ impl Device {
             address_mode_v: vk::SamplerAddressMode::REPEAT,
             address_mode_w: vk::SamplerAddressMode::REPEAT,
             mip_lod_bias: 0.0,
-            anisotropy_enable: vk::TRUE,
-            max_anisotropy: 16.0,
+            anisotropy_enable: if anisotropy { vk::TRUE } else { vk::FALSE },
+            max_anisotropy: if anisotropy {
+                self.pdevice.limits().max_sampler_anisotropy.min(16.0)
+            } else {
+                1.0
+            },
             compare_enable: vk::FALSE,
             compare_op: vk::CompareOp::ALWAYS,
             min_lod: 0.0,
@@ -462,12 +533,8 @@ impl Device {
             unnormalized_coordinates: vk::FALSE,
         };
 
-        let sampler = unsafe {
-            self.raw
-                .create_sampler(&sampler_create_info, None)
-                .expect("Failed to create Sampler!")
-        };
-        
+        let sampler = unsafe { self.raw.create_sampler(&sampler_create_info, None)? };
+
         Ok(sampler)
     }
 }
@@ -568,13 +635,16 @@ pub fn find_memory_type(
     type_filter: u32,
     required_properties: vk::MemoryPropertyFlags,
     mem_properties: &vk::PhysicalDeviceMemoryProperties,
-) -> u32 {
+) -> std::result::Result<u32, BackendError> {
     for (i, memory_type) in mem_properties.memory_types.iter().enumerate() {
         if (type_filter & (1 << i)) > 0 && memory_type.property_flags.contains(required_properties)
         {
-            return i as u32;
+            return std::result::Result::Ok(i as u32);
         }
     }
 
-    panic!("Failed to find suitable memory type!")
+    Err(BackendError::NoSuitableMemoryType {
+        type_bits: type_filter,
+        flags: required_properties,
+    })
 }
\ No newline at end of file
@@ -1,39 +1,136 @@
 use crate::platforms;
 use crate::constants::*;
 use crate::tools;
+use crate::vulkan::error::BackendError;
 use crate::debug::*;
 
 use anyhow::Ok;
-use ash::extensions::ext;
 use ash::vk;
 use std::ffi::{CStr, CString};
-use std::os::raw::c_void;
+use ash::extensions::ext::DebugUtils;
 use std::ptr;
 use anyhow::Result;
 
-unsafe extern "system" fn vulkan_debug_utils_callback(
-    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
-    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
-) -> vk::Bool32 {
-    let severity = match message_severity {
-        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => "[Verbose]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => "[Warning]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => "[Error]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => "[Info]",
-        _ => "[Unknown]",
-    };
-    let types = match message_type {
-        vk::DebugUtilsMessageTypeFlagsEXT::GENERAL => "[General]",
-        vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE => "[Performance]",
-        vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "[Validation]",
-        _ => "[Unknown]",
-    };
-    let message = CStr::from_ptr((*p_callback_data).p_message);
-    println!("[Debug]{}{}{:?}", severity, types, message);
-
-    vk::FALSE
+#[derive(Clone)]
+pub struct InstanceDesc {
+    pub app_name: String,
+    pub app_version: u32,
+    pub engine_name: String,
+    pub engine_version: u32,
+    // highest version the app wants, clamped to what the loader supports
+    pub api_version: u32,
+    pub required_layers: Vec<String>,
+    pub optional_layers: Vec<String>,
+    pub required_extensions: Vec<String>,
+    pub optional_extensions: Vec<String>,
+    // validation layers and debug utils, both skipped with a warning if not installed
+    pub graphics_debugging: bool,
+    pub debug_messenger: DebugMessengerDesc,
+    // only applied when the validation layer ends up enabled
+    pub validation_features: ValidationFeatures,
+}
+
+impl Default for InstanceDesc {
+    fn default() -> Self {
+        InstanceDesc {
+            app_name: WINDOW_TITLE.to_owned(),
+            app_version: vk::make_api_version(0, 1, 0, 0),
+            engine_name: "Vulkan Engine".to_owned(),
+            engine_version: vk::make_api_version(0, 1, 0, 0),
+            api_version: vk::make_api_version(0, 1, 2, 0),
+            required_layers: Vec::new(),
+            optional_layers: Vec::new(),
+            required_extensions: Vec::new(),
+            optional_extensions: Vec::new(),
+            graphics_debugging: VALIDATION.is_enable,
+            debug_messenger: Default::default(),
+            validation_features: Default::default(),
+        }
+    }
+}
+
+impl InstanceDesc {
+    // instance able to create surfaces for this platform's windows
+    pub fn windowed() -> Self {
+        let mut desc = InstanceDesc::default();
+        for name in platforms::required_extension_names() {
+            desc = desc.require_extension(&name.to_string_lossy());
+        }
+        desc
+    }
+
+    // instance without any surface extension, for machines that have no display
+    pub fn headless() -> Self {
+        InstanceDesc::default()
+    }
+
+    pub fn app(mut self, name: &str, version: u32) -> Self {
+        self.app_name = name.to_owned();
+        self.app_version = version;
+        self
+    }
+
+    pub fn engine(mut self, name: &str, version: u32) -> Self {
+        self.engine_name = name.to_owned();
+        self.engine_version = version;
+        self
+    }
+
+    pub fn api_version(mut self, api_version: u32) -> Self {
+        self.api_version = api_version;
+        self
+    }
+
+    pub fn require_layer(mut self, name: &str) -> Self {
+        self.required_layers.push(name.to_owned());
+        self
+    }
+
+    pub fn optional_layer(mut self, name: &str) -> Self {
+        self.optional_layers.push(name.to_owned());
+        self
+    }
+
+    pub fn require_extension(mut self, name: &str) -> Self {
+        self.required_extensions.push(name.to_owned());
+        self
+    }
+
+    pub fn optional_extension(mut self, name: &str) -> Self {
+        self.optional_extensions.push(name.to_owned());
+        self
+    }
+
+    pub fn graphics_debugging(mut self, enable: bool) -> Self {
+        self.graphics_debugging = enable;
+        self
+    }
+
+    pub fn debug_messenger(mut self, debug_messenger: DebugMessengerDesc) -> Self {
+        self.debug_messenger = debug_messenger;
+        self
+    }
+
+    pub fn validation_features(mut self, validation_features: ValidationFeatures) -> Self {
+        self.validation_features = validation_features;
+        self
+    }
+
+    // shader debugPrintfEXT output, collected on the instance unless `capture` has a callback
+    pub fn debug_printf(mut self, capture: DebugPrintfCapture) -> Self {
+        self.graphics_debugging = true;
+        self.validation_features.debug_printf = true;
+        self.debug_messenger = self.debug_messenger.debug_printf(capture);
+        self
+    }
+
+    // for tests: turns on validation and keeps all of its messages on the instance; with
+    // `panic_on_error` the next `take_validation_messages` panics on the first error
+    pub fn capture_validation(mut self, panic_on_error: bool) -> Self {
+        self.graphics_debugging = true;
+        self.debug_messenger = self.debug_messenger.capture_validation(panic_on_error);
+        self
+    }
 }
 
 pub struct Instance {
@@ -49,154 +146,307 @@ pub struct Instance {
     */
     pub(crate) debug_utils: ash::extensions::ext::DebugUtils,
     pub(crate) debug_merssager: vk::DebugUtilsMessengerEXT,
+    // the messenger callback reads this through its user data pointer, so it's boxed
+    pub(crate) debug_messenger_desc: Box<DebugMessengerDesc>,
+    pub(crate) validation_enabled: bool,
+    pub(crate) debug_utils_enabled: bool,
+    pub(crate) api_version: u32,
+    pub(crate) validation_features: ValidationFeatures,
+    pub(crate) enabled_layers: Vec<String>,
+    pub(crate) enabled_extensions: Vec<String>,
 }
 
 impl Instance {
-    pub fn new() -> Result<Instance> {
+    pub fn create(desc: InstanceDesc) -> Result<Instance> {
         let entry =  unsafe { ash::Entry::new()? };
-        let instance = Instance::create_instance(&entry).unwrap();
-        let (debug_utils, debug_merssager) = setup_debug_utils(VALIDATION.is_enable, &entry, &instance);
+
+        let api_version = Instance::negotiate_api_version(&entry, desc.api_version)?;
+
+        let mut optional_layers = desc.optional_layers.clone();
+        let mut optional_extensions = desc.optional_extensions.clone();
+        if desc.graphics_debugging {
+            optional_layers.extend(VALIDATION.required_validation_layers.iter().map(|name| name.to_string()));
+            optional_extensions.push(DebugUtils::name().to_string_lossy().into_owned());
+            if desc.validation_features.any() {
+                optional_extensions.push(vk::ExtValidationFeaturesFn::name().to_string_lossy().into_owned());
+            }
+        }
+
+        let available_layers: Vec<String> = entry
+            .enumerate_instance_layer_properties()?
+            .iter()
+            .map(|layer| tools::vk_to_string(&layer.layer_name))
+            .collect();
+        let enabled_layers = Instance::negotiate("layer", &available_layers, &desc.required_layers, &optional_layers)?;
+
+        // layers can bring their own extensions, so ask each enabled layer as well
+        let mut available_extensions = Instance::enumerate_extension_names(&entry, None)?;
+        for layer_name in enabled_layers.iter() {
+            let layer_name = CString::new(layer_name.as_str())?;
+            available_extensions.extend(Instance::enumerate_extension_names(&entry, Some(&layer_name))?);
+        }
+        let enabled_extensions = Instance::negotiate("extension", &available_extensions, &desc.required_extensions, &optional_extensions)?;
+
+        let validation_enabled = VALIDATION
+            .required_validation_layers
+            .iter()
+            .all(|name| enabled_layers.iter().any(|layer| layer == name));
+        let debug_utils_enabled = enabled_extensions
+            .iter()
+            .any(|name| *name == DebugUtils::name().to_string_lossy());
+        let debug_messenger_enabled = desc.graphics_debugging && debug_utils_enabled;
+
+        let mut validation_features = ValidationFeatures::default();
+        if desc.graphics_debugging && validation_enabled && desc.validation_features.any() {
+            if enabled_extensions
+                .iter()
+                .any(|name| *name == vk::ExtValidationFeaturesFn::name().to_string_lossy())
+            {
+                validation_features = desc.validation_features;
+            } else {
+                log::warn!("validation features requested, but VK_EXT_validation_features is not available");
+            }
+        }
+        // older layers refuse to run both at once
+        if validation_features.gpu_assisted && validation_features.debug_printf {
+            log::warn!("GPU-assisted validation and debugPrintf can't be combined, keeping GPU-assisted validation");
+            validation_features.debug_printf = false;
+        }
+
+        let mut debug_messenger_desc = Box::new(desc.debug_messenger.clone());
+        if validation_features.debug_printf && debug_messenger_desc.debug_printf.is_none() {
+            debug_messenger_desc.debug_printf = Some(Default::default());
+        }
+        let debug_messenger = debug_messenger_enabled.then_some(&*debug_messenger_desc);
+
+        let instance = Instance::create_instance(&entry, &desc, api_version, &enabled_layers, &enabled_extensions, debug_messenger, validation_features)?;
+        let (debug_utils, debug_merssager) =
+            match setup_debug_utils(debug_messenger_enabled, &entry, &instance, &debug_messenger_desc) {
+                std::result::Result::Ok(debug_utils) => debug_utils,
+                Err(err) => {
+                    unsafe { instance.destroy_instance(None) };
+                    return Err(err);
+                }
+            };
 
         Ok(Instance {
             _entry: entry,
             raw: instance,
             debug_utils,
             debug_merssager,
+            debug_messenger_desc,
+            validation_enabled,
+            debug_utils_enabled,
+            api_version,
+            validation_features,
+            enabled_layers,
+            enabled_extensions,
         })
     }
 
-    fn create_instance(entry: &ash::Entry) -> Result<ash::Instance> {
-        if VALIDATION.is_enable && Instance::check_validation_layer_support(entry) == false {
-            panic!("Validation layers requested, but not available!");
+    pub fn validation_enabled(&self) -> bool {
+        self.validation_enabled
+    }
+
+    // drains everything captured so far, always empty unless capture was requested
+    pub fn take_validation_messages(&self) -> Vec<DebugMessage> {
+        match &self.debug_messenger_desc.capture {
+            Some(capture) => capture.take(),
+            None => Vec::new(),
         }
+    }
 
-        let app_name = CString::new(WINDOW_TITLE).unwrap();
-        let engine_name = CString::new("Vulkan Engine").unwrap();
+    // drains shader printf output, call once per frame to keep records grouped by frame
+    pub fn take_debug_printf(&self) -> Vec<DebugPrintfRecord> {
+        match &self.debug_messenger_desc.debug_printf {
+            Some(debug_printf) => debug_printf.take(),
+            None => Vec::new(),
+        }
+    }
 
-        let app_desc = vk::ApplicationInfo::builder().api_version(vk::make_api_version(0, 1, 2, 0));
+    pub fn assert_no_validation_errors(&self) {
+        let errors: Vec<String> = self
+            .take_validation_messages()
+            .iter()
+            .filter(|message| message.severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR))
+            .map(|message| message.to_string())
+            .collect();
 
-        // let app_info = vk::ApplicationInfo {
-        //     s_type: vk::StructureType::APPLICATION_INFO,
-        //     p_next: ptr::null(),
-        //     p_application_name: app_name.as_ptr(),
-        //     application_version: APPLICATION_VERSION,
-        //     p_engine_name: engine_name.as_ptr(),
-        //     engine_version: ENGINE_VERSION,
-        //     api_version: API_VERSION,
-        // };
+        assert!(errors.is_empty(), "Validation errors:\n{}", errors.join("\n"));
+    }
 
-        let debug_utils_create_info = populate_debug_messenger_create_info();
+    pub fn debug_utils_enabled(&self) -> bool {
+        self.debug_utils_enabled
+    }
 
-        let extension_names = platforms::required_extension_names();
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
 
-        let requred_validation_layer_raw_names: Vec<CString> = VALIDATION
-            .required_validation_layers
-            .iter()
-            .map(|layer_name| CString::new(*layer_name).unwrap())
-            .collect();
+    pub fn validation_features(&self) -> ValidationFeatures {
+        self.validation_features
+    }
 
-        let enable_layer_names: Vec<*const i8> = requred_validation_layer_raw_names
-            .iter()
-            .map(|layer_name| layer_name.as_ptr())
-            .collect();
+    pub fn enabled_layers(&self) -> &[String] {
+        &self.enabled_layers
+    }
 
-        // let create_info = vk::InstanceCreateInfo {
-        //     s_type: vk::StructureType::INSTANCE_CREATE_INFO,
-        //     p_next: if VALIDATION.is_enable {
-        //         &debug_utils_create_info as *const vk::DebugUtilsMessengerCreateInfoEXT
-        //             as *const c_void
-        //     } else {
-        //         ptr::null()
-        //     },
-        //     flags: vk::InstanceCreateFlags::empty(),
-        //     p_application_info: &app_desc,
-        //     pp_enabled_layer_names: if VALIDATION.is_enable {
-        //         enable_layer_names.as_ptr()
-        //     } else {
-        //         ptr::null()
-        //     },
-        //     enabled_layer_count: if VALIDATION.is_enable {
-        //         enable_layer_names.len()
-        //     } else {
-        //         0
-        //     } as u32,
-        //     pp_enabled_extension_names: extension_names.as_ptr(),
-        //     enabled_extension_count: extension_names.len() as u32,
-        // };
-
-        let instance_desc = vk::InstanceCreateInfo::builder()
-        .application_info(&app_desc)
-        .enabled_layer_names(&enable_layer_names)
-        .enabled_extension_names(&extension_names);
-    
-        let instance: ash::Instance = unsafe {
+    pub fn enabled_extensions(&self) -> &[String] {
+        &self.enabled_extensions
+    }
+
+    fn negotiate_api_version(entry: &ash::Entry, requested: u32) -> Result<u32> {
+        // 1.0 loaders don't have vkEnumerateInstanceVersion
+        let supported = entry
+            .try_enumerate_instance_version()?
+            .unwrap_or_else(|| vk::make_api_version(0, 1, 0, 0));
+
+        if supported < requested {
+            log::warn!(
+                "requested Vulkan {}.{}, but the loader only supports {}.{}",
+                vk::api_version_major(requested),
+                vk::api_version_minor(requested),
+                vk::api_version_major(supported),
+                vk::api_version_minor(supported),
+            );
+            return Ok(supported);
+        }
+
+        Ok(requested)
+    }
+
+    fn enumerate_extension_names(entry: &ash::Entry, layer_name: Option<&CStr>) -> Result<Vec<String>> {
+        // ash only exposes the loader-wide query, so go through the raw fn for layers
+        let layer_name = layer_name.map_or(ptr::null(), |name| name.as_ptr());
+        let mut count = 0;
+        let extensions = unsafe {
             entry
-                .create_instance(&instance_desc, None)
-                .expect("Failed to create instance!")
+                .fp_v1_0()
+                .enumerate_instance_extension_properties(layer_name, &mut count, ptr::null_mut())
+                .result()?;
+            let mut extensions = Vec::with_capacity(count as usize);
+            entry
+                .fp_v1_0()
+                .enumerate_instance_extension_properties(layer_name, &mut count, extensions.as_mut_ptr())
+                .result()?;
+            extensions.set_len(count as usize);
+            extensions
         };
 
-        Ok(instance)
+        Ok(extensions
+            .iter()
+            .map(|extension: &vk::ExtensionProperties| tools::vk_to_string(&extension.extension_name))
+            .collect())
     }
 
-    fn check_validation_layer_support(entry: &ash::Entry) -> bool {
-        // if support validation layer, then return true
-
-        let layer_properties = entry
-            .enumerate_instance_layer_properties()
-            .expect("Failed to enumerate Instance Layers Properties!");
+    // required names that are missing fail creation, optional ones are dropped with a warning
+    fn negotiate(
+        kind: &str,
+        available: &[String],
+        required: &[String],
+        optional: &[String],
+    ) -> Result<Vec<String>> {
+        let missing: Vec<&String> = required
+            .iter()
+            .filter(|name| !available.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(BackendError::UnsupportedFeature(format!(
+                "required instance {}s not available: {:?}",
+                kind, missing
+            )));
+        }
 
-        if layer_properties.len() <= 0 {
-            eprintln!("No available layers.");
-            return false;
-        } else {
-            println!("Instance Available Layers: ");
-            for layer in layer_properties.iter() {
-                let layer_name = tools::vk_to_string(&layer.layer_name);
-                println!("\t{}", layer_name);
+        let mut enabled: Vec<String> = Vec::new();
+        for name in required.iter() {
+            if !enabled.contains(name) {
+                enabled.push(name.clone());
             }
         }
-
-        for required_layer_name in VALIDATION.required_validation_layers.iter() {
-            let mut is_layer_found = false;
-
-            for layer_property in layer_properties.iter() {
-                let test_layer_name = tools::vk_to_string(&layer_property.layer_name);
-                if (*required_layer_name) == test_layer_name {
-                    is_layer_found = true;
-                    break;
-                }
+        for name in optional.iter() {
+            if enabled.contains(name) {
+                continue;
             }
-
-            if is_layer_found == false {
-                return false;
+            if available.contains(name) {
+                enabled.push(name.clone());
+            } else {
+                log::warn!("optional instance {} {} not available, skipping it", kind, name);
             }
         }
 
-        true
+        Ok(enabled)
     }
-}
 
-fn populate_debug_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT {
-    vk::DebugUtilsMessengerCreateInfoEXT {
-        s_type: vk::StructureType::DEBUG_UTILS_MESSENGER_CREATE_INFO_EXT,
-        p_next: ptr::null(),
-        flags: vk::DebugUtilsMessengerCreateFlagsEXT::empty(),
-        message_severity: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING |
-            // vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE |
-            // vk::DebugUtilsMessageSeverityFlagsEXT::INFO |
-            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
-        message_type: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-            | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
-            | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
-        pfn_user_callback: Some(vulkan_debug_utils_callback),
-        p_user_data: ptr::null_mut(),
+    fn create_instance(
+        entry: &ash::Entry,
+        desc: &InstanceDesc,
+        api_version: u32,
+        layer_names: &[String],
+        extension_names: &[String],
+        debug_messenger: Option<&DebugMessengerDesc>,
+        validation_features: ValidationFeatures,
+    ) -> Result<ash::Instance> {
+        let app_name = CString::new(desc.app_name.as_str())?;
+        let engine_name = CString::new(desc.engine_name.as_str())?;
+
+        let app_desc = vk::ApplicationInfo::builder()
+            .application_name(&app_name)
+            .application_version(desc.app_version)
+            .engine_name(&engine_name)
+            .engine_version(desc.engine_version)
+            .api_version(api_version);
+
+        let layer_raw_names: Vec<CString> = layer_names
+            .iter()
+            .map(|name| CString::new(name.as_str()))
+            .collect::<std::result::Result<_, _>>()?;
+        let enable_layer_names: Vec<*const i8> = layer_raw_names
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect();
+
+        let extension_raw_names: Vec<CString> = extension_names
+            .iter()
+            .map(|name| CString::new(name.as_str()))
+            .collect::<std::result::Result<_, _>>()?;
+        let enable_extension_names: Vec<*const i8> = extension_raw_names
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect();
+
+        let mut instance_desc = vk::InstanceCreateInfo::builder()
+        .application_info(&app_desc)
+        .enabled_layer_names(&enable_layer_names)
+        .enabled_extension_names(&enable_extension_names);
+
+        // lets the messenger also report issues in create/destroy instance
+        let mut debug_utils_create_info;
+        if let Some(debug_messenger) = debug_messenger {
+            debug_utils_create_info = populate_debug_messenger_create_info(debug_messenger);
+            instance_desc = instance_desc.push_next(&mut debug_utils_create_info);
+        }
+
+        let enabled_validation_features = validation_features.to_vk();
+        let mut validation_features_info = vk::ValidationFeaturesEXT::builder()
+            .enabled_validation_features(&enabled_validation_features);
+        if validation_features.any() {
+            instance_desc = instance_desc.push_next(&mut validation_features_info);
+        }
+    
+        let instance: ash::Instance = unsafe {
+            entry
+                .create_instance(&instance_desc, None)
+                .map_err(|err| anyhow::anyhow!("Failed to create instance: {}", err))?
+        };
+
+        Ok(instance)
     }
 }
 
 impl Drop for Instance {
     fn drop(&mut self) {
         unsafe {
-            if VALIDATION.is_enable {
+            if self.debug_merssager != vk::DebugUtilsMessengerEXT::null() {
                 self.debug_utils
                     .destroy_debug_utils_messenger(self.debug_merssager, None);
             }
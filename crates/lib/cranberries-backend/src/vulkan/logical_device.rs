@@ -1,13 +1,10 @@
-use ash::vk::{self, Buffer};
-use ash::vk::Handle;
+use ash::vk;
 
-use std::fmt::Error;
 use std::sync::Arc;
 use anyhow::{Result, Ok};
 
 use crate::vulkan::instance::Instance;
 use crate::vulkan::physical_device::PhysicalDevice;
-use crate::vulkan::surface::Surface;
 use crate::vulkan::buffer::UniformBufferObject;
 use crate::debug;
 use crate::vulkan::buffer;
@@ -15,11 +12,18 @@ use crate::vulkan::buffer;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::ptr;
-use std::sync::Mutex;
+use std::mem::ManuallyDrop;
+use std::sync::{Mutex, PoisonError};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use super::image::ImageViewDesc;
-use super::physical_device;
-use super::physical_device::QueueFamilyIndices;
+use super::physical_device::{DeviceFeatures, QueueFamilyIndices};
+use super::features::{DeviceFeature, DeviceFeatureRequest, DeviceFeatureSet};
+use super::timeline::{QueueTimeline, QueueType};
+use super::command::{CommandRecorder, ThreadCommandPools};
+use super::error::BackendError;
+use super::crash::CrashTracker;
+use super::queue::{Queue, SubmitBatch};
 
 
 
@@ -33,16 +37,19 @@ pub struct Device {
     pub(crate) pdevice: Arc<PhysicalDevice>,
     pub(crate) instance: Arc<Instance>,
     //pub universal_queue: vk::Queue,
-    pub _graphics_queue: vk::Queue,
-    pub _present_queue: vk::Queue,
+    pub(crate) graphics_queue: Arc<Queue>,
+    // `None` for headless devices
+    pub(crate) present_queue: Option<Arc<Queue>>,
+    // may alias the graphics queue when the device has no dedicated family
+    pub(crate) compute_queue: Arc<Queue>,
+    pub(crate) transfer_queue: Arc<Queue>,
+    pub(crate) enabled_features: DeviceFeatureSet,
+    pub(crate) enabled_extensions: Vec<String>,
     /*
     pub(crate) global_allocator: Arc<Mutex<VulkanAllocator>>,
     pub(crate) immutable_samplers: HashMap<SamplerDesc, vk::Sampler>,
     pub(crate) setup_cb: Mutex<CommandBuffer>,
 
-    pub(crate) crash_tracking_buffer: Buffer,
-    pub(crate) crash_marker_names: Mutex<CrashMarkerNames>,
-
     pub acceleration_structure_ext: khr::AccelerationStructure,
     pub ray_tracing_pipeline_ext: khr::RayTracingPipeline,
     // pub ray_query_ext: khr::RayQuery,
@@ -52,28 +59,43 @@ pub struct Device {
 
     ray_tracing_enabled: bool,
     */
-    pub setup_cb: Mutex<CommandBuffer>,
-    pub frames: [Mutex<Arc<DeviceFrame>>; 2],
+    // dropped by hand in `Drop`, before the device it was created from
+    pub setup_cb: ManuallyDrop<Mutex<CommandBuffer>>,
+    // ring of `frames_in_flight` slots, `frame_index` counts every finished frame
+    pub frames: Vec<Mutex<Arc<DeviceFrame>>>,
+    pub(crate) frame_index: AtomicUsize,
+    // where dropped buffers and images wait for the GPU, see `collect_garbage`
+    pub(crate) release_queue: Arc<ReleaseQueue>,
+    // indexed by `QueueType`, empty unless timeline semaphores are enabled
+    pub(crate) timelines: Vec<Arc<QueueTimeline>>,
+    // `None` unless created with `crash_tracking`
+    pub(crate) crash_tracker: Option<CrashTracker>,
 }
 
 impl Device {
     pub fn create(
         instance: &Arc<Instance>,
         physical_device: &Arc<PhysicalDevice>,
-        surface: &Surface,
         validation: &debug::ValidationInfo,
         device_extensions: &debug::DeviceExtension,
+        features: &DeviceFeatureRequest,
+        frames_in_flight: usize,
+        crash_tracking: bool,
     ) -> Result<Device> {
-        let indices = PhysicalDevice::find_queue_family(
-            &instance.raw,
-            physical_device.raw,
-            surface,
-        );
+        if frames_in_flight == 0 {
+            anyhow::bail!(BackendError::InvalidArgument("at least one frame in flight is needed".to_owned()));
+        }
+
+        let indices = &physical_device.queue_family_index;
+
+        let enabled_features = features.resolve(physical_device.supported_features())?;
 
         use std::collections::HashSet;
         let mut unique_queue_families = HashSet::new();
         unique_queue_families.insert(indices.graphics_family.unwrap());
-        unique_queue_families.insert(indices.present_family.unwrap());
+        for family in [indices.present_family, indices.compute_family, indices.transfer_family].into_iter().flatten() {
+            unique_queue_families.insert(family);
+        }
 
 
         let queue_priorities = [1.0_f32];
@@ -90,27 +112,68 @@ impl Device {
             queue_create_infos.push(queue_create_info);
         }
 
-        let physical_device_features = vk::PhysicalDeviceFeatures {
-            sampler_anisotropy: vk::TRUE,
-            ..Default::default() // default just enable no feature.
-        };
+        // start from nothing and switch on exactly what was resolved above
+        let mut device_features = DeviceFeatures::default();
+        for feature in enabled_features.iter() {
+            feature.enable(&mut device_features);
+        }
+
+        let use_features2 = DeviceFeatures::has_features2(instance, physical_device.properties());
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+            .features(device_features.core)
+            .push_next(&mut device_features.vulkan_11)
+            .push_next(&mut device_features.vulkan_12);
+        if enabled_features.contains(DeviceFeature::Synchronization2) {
+            features2 = features2.push_next(&mut device_features.synchronization2);
+        }
+        if enabled_features.contains(DeviceFeature::DynamicRendering) {
+            features2 = features2.push_next(&mut device_features.dynamic_rendering);
+        }
+        let features2 = features2.build();
 
         let requred_validation_layer_raw_names: Vec<CString> = validation
         .required_validation_layers
         .iter()
-        .map(|layer_name| CString::new(*layer_name).unwrap())
-        .collect();
+        .map(|layer_name| CString::new(*layer_name))
+        .collect::<std::result::Result<_, _>>()?;
     
         let enable_layer_names: Vec<*const c_char> = requred_validation_layer_raw_names
         .iter()
         .map(|layer_name| layer_name.as_ptr())
         .collect();
 
-        let enable_extension_names = device_extensions.get_extensions_raw_names();
+        // features like synchronization2 bring their extension along
+        let mut device_extensions = enabled_features
+            .iter()
+            .filter_map(|feature| feature.extension())
+            .fold(device_extensions.clone(), |extensions, name| extensions.require(&name.to_string_lossy()));
+        // debugPrintf shaders use non-semantic instructions, core only from 1.3
+        if instance.validation_features().debug_printf {
+            device_extensions = device_extensions.optional("VK_KHR_shader_non_semantic_info");
+        }
+        // pipelined breadcrumbs, without it they need a barrier each
+        if crash_tracking {
+            device_extensions = device_extensions.optional("VK_AMD_buffer_marker");
+        }
+
+        let enabled_extensions = device_extensions.resolve(physical_device.extensions())?;
+        let enabled_extension_names = enabled_extensions
+            .iter()
+            .map(|name| CString::new(name.as_str()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let enable_extension_names: Vec<*const c_char> = enabled_extension_names
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect();
 
         let device_create_info = vk::DeviceCreateInfo {
             s_type: vk::StructureType::DEVICE_CREATE_INFO,
-            p_next: ptr::null(),
+            // with features2 in the chain, `p_enabled_features` has to stay null
+            p_next: if use_features2 {
+                &features2 as *const vk::PhysicalDeviceFeatures2 as *const std::os::raw::c_void
+            } else {
+                ptr::null()
+            },
             flags: vk::DeviceCreateFlags::empty(),
             queue_create_info_count: queue_create_infos.len() as u32,
             p_queue_create_infos: queue_create_infos.as_ptr(),
@@ -126,7 +189,11 @@ impl Device {
             },
             enabled_extension_count: enable_extension_names.len() as u32,
             pp_enabled_extension_names: enable_extension_names.as_ptr(),
-            p_enabled_features: &physical_device_features,
+            p_enabled_features: if use_features2 {
+                ptr::null()
+            } else {
+                &device_features.core
+            },
         };
 
         //println!("{:#?}", device_create_info);
@@ -134,120 +201,363 @@ impl Device {
         let device: ash::Device = unsafe {
             instance
                 .raw
-                .create_device(physical_device.raw, &device_create_info, None)
-                .expect("Failed to create logical Device!")
+                .create_device(physical_device.raw, &device_create_info, None)?
+        };
+        let mut guard = CreateGuard {
+            device: Some(device.clone()),
+            queues: Vec::new(),
         };
 
-        let graphics_queue = 
-            unsafe { device.get_device_queue(indices.graphics_family.unwrap(), 0) };
-        let present_queue =
-            unsafe { device.get_device_queue(indices.present_family.unwrap(), 0) };
-
-        let frame0 = DeviceFrame::new(physical_device, &device, &physical_device.queue_family_index);
-
-        let frame1 = DeviceFrame::new(physical_device, &device, &physical_device.queue_family_index);
-
-        let setup_cb = CommandBuffer::create(&device, physical_device.queue_family_index.graphics_family.unwrap()).unwrap();
-        
-        Ok(Device {
+        // families without a dedicated queue get the graphics one, and a handle that shows up
+        // twice has to share its `Queue` so submissions to it stay serialized
+        let timelines_enabled = enabled_features.contains(DeviceFeature::TimelineSemaphore);
+        let mut queue_for = |family_index: u32| -> Result<Arc<Queue>> {
+            let raw = unsafe { device.get_device_queue(family_index, 0) };
+            match guard.queues.iter().find(|queue| queue.raw == raw) {
+                Some(queue) => Ok(queue.clone()),
+                None => {
+                    let queue = Arc::new(Queue::new(&device, raw, family_index, timelines_enabled)?);
+                    guard.queues.push(queue.clone());
+                    Ok(queue)
+                }
+            }
+        };
+        let graphics_family = indices.graphics_family.unwrap();
+        let graphics_queue = queue_for(graphics_family)?;
+        let present_queue = indices.present_family.map(&mut queue_for).transpose()?;
+        let compute_queue = queue_for(indices.compute_family.unwrap_or(graphics_family))?;
+        let transfer_queue = queue_for(indices.transfer_family.unwrap_or(graphics_family))?;
+
+        // aliased queues hand out the same semaphore
+        let timelines = [&graphics_queue, &compute_queue, &transfer_queue]
+            .into_iter()
+            .filter_map(|queue| QueueTimeline::new(queue.clone()).map(Arc::new))
+            .collect();
+
+        let frames = (0..frames_in_flight)
+            .map(|_| {
+                DeviceFrame::new(physical_device, &device, &physical_device.queue_family_index)
+                    .map(|frame| Mutex::new(Arc::new(frame)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let setup_cb = CommandBuffer::create(&device, physical_device.queue_family_index.graphics_family.unwrap())?;
+
+        // from here on `Device`'s drop cleans up
+        guard.device = None;
+        let queues = std::mem::take(&mut guard.queues);
+
+        let mut device = Device {
             raw: device,
             pdevice: physical_device.clone(),
             instance: instance.clone(),
-            _graphics_queue: graphics_queue,
-            _present_queue: present_queue,
-            setup_cb: Mutex::new(setup_cb),
-            frames: [
-                Mutex::new(Arc::new(frame0)),
-                Mutex::new(Arc::new(frame1)),
-            ],
-        })
+            graphics_queue,
+            present_queue,
+            compute_queue,
+            transfer_queue,
+            enabled_features,
+            enabled_extensions,
+            setup_cb: ManuallyDrop::new(Mutex::new(setup_cb)),
+            frames,
+            frame_index: AtomicUsize::new(0),
+            release_queue: Arc::new(ReleaseQueue::new(queues)),
+            timelines,
+            crash_tracker: None,
+        };
+
+        if crash_tracking {
+            device.crash_tracker = Some(CrashTracker::create(&device)?);
+        }
+
+        if device.has_dedicated_compute() {
+            device.set_debug_name(device.compute_queue.raw, "compute queue");
+        }
+        if device.has_dedicated_transfer() {
+            device.set_debug_name(device.transfer_queue.raw, "transfer queue");
+        }
+
+        for (queue_type, timeline) in QueueType::ALL.iter().zip(device.timelines.iter()) {
+            device.set_debug_name(timeline.semaphore, &format!("{:?} timeline", queue_type).to_lowercase());
+        }
+
+        device.set_debug_name(device.setup_cb.lock().unwrap().raw, "setup command buffer");
+        for (i, frame) in device.frames.iter().enumerate() {
+            let frame = frame.lock().unwrap();
+            device.set_debug_name(frame.main_command_buffer.raw, &format!("frame {} main command buffer", i));
+            device.set_debug_name(frame.main_command_buffer.submit_done_fence, &format!("frame {} submit fence", i));
+            device.set_debug_name(frame.swapchain_acquired_semaphore, &format!("frame {} swapchain acquired semaphore", i));
+        }
+
+        Ok(device)
     }
 
-    pub fn begin_frame(&self) -> Arc<DeviceFrame> {
-        let mut frame0 = self.frames[0].lock().unwrap();
+    pub fn graphics_queue(&self) -> &Queue {
+        &self.graphics_queue
+    }
+
+    pub fn present_queue(&self) -> Option<&Queue> {
+        self.present_queue.as_deref()
+    }
+
+    // the same `Queue` as `graphics_queue` unless `has_dedicated_compute`
+    pub fn compute_queue(&self) -> &Queue {
+        &self.compute_queue
+    }
+
+    pub fn transfer_queue(&self) -> &Queue {
+        &self.transfer_queue
+    }
+
+    pub fn queue(&self, queue: QueueType) -> &Queue {
+        match queue {
+            QueueType::Graphics => &self.graphics_queue,
+            QueueType::Compute => &self.compute_queue,
+            QueueType::Transfer => &self.transfer_queue,
+        }
+    }
+
+    pub fn queue_family_indices(&self) -> &QueueFamilyIndices {
+        &self.pdevice.queue_family_index
+    }
+
+    // what `DeviceFeatureRequest::resolve` settled on, optional features included
+    pub fn enabled_features(&self) -> DeviceFeatureSet {
+        self.enabled_features
+    }
+
+    pub fn is_feature_enabled(&self, feature: DeviceFeature) -> bool {
+        self.enabled_features.contains(feature)
+    }
+
+    // required extensions plus whichever optional ones the device had
+    pub fn enabled_extensions(&self) -> &[String] {
+        &self.enabled_extensions
+    }
+
+    pub fn is_extension_enabled(&self, name: &str) -> bool {
+        self.enabled_extensions.iter().any(|extension| extension == name)
+    }
+
+    pub fn has_dedicated_compute(&self) -> bool {
+        self.pdevice.queue_family_index.has_dedicated_compute()
+    }
+
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.pdevice.queue_family_index.has_dedicated_transfer()
+    }
+
+    // shows up in validation messages and captures, does nothing without debug utils
+    pub fn set_debug_name<H: vk::Handle>(&self, object: H, name: &str) {
+        if !self.instance.debug_utils_enabled() {
+            return;
+        }
+
+        let name = CString::new(name).unwrap_or_default();
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(object.as_raw())
+            .object_name(&name);
+
+        unsafe {
+            // naming is best-effort, a failure here shouldn't take the app down
+            let _ = self
+                .instance
+                .debug_utils
+                .debug_utils_set_object_name(self.raw.handle(), &name_info);
+        }
+    }
+
+    // groups the commands recorded until the returned scope drops, e.g. "shadow" or "gbuffer"
+    pub fn debug_label_scope(&self, command_buffer: vk::CommandBuffer, name: &str) -> DebugLabelScope<'_> {
+        let active = self.instance.debug_utils_enabled();
+        if active {
+            let name = CString::new(name).unwrap_or_default();
+            let label = vk::DebugUtilsLabelEXT::builder().label_name(&name);
+            unsafe {
+                self.instance
+                    .debug_utils
+                    .cmd_begin_debug_utils_label(command_buffer, &label);
+            }
+        }
+
+        DebugLabelScope {
+            device: self,
+            command_buffer,
+            active,
+        }
+    }
+
+    pub fn insert_debug_label(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        if !self.instance.debug_utils_enabled() {
+            return;
+        }
+
+        let name = CString::new(name).unwrap_or_default();
+        let label = vk::DebugUtilsLabelEXT::builder().label_name(&name);
+        unsafe {
+            self.instance
+                .debug_utils
+                .cmd_insert_debug_utils_label(command_buffer, &label);
+        }
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frames.len()
+    }
+
+    // waits until the GPU is done with the slot's previous use, then starts recording its
+    // main command buffer; record into `frame.main_command_buffer` until `finish_frame`
+    pub fn begin_frame(&self) -> Result<Arc<DeviceFrame>> {
+        let slot = self.frame_index.load(Ordering::Acquire) % self.frames.len();
+        let mut frame = self.frames[slot].lock().unwrap();
         {
-            let frame0: &mut DeviceFrame = Arc::get_mut(&mut frame0).unwrap_or_else(|| {
-                panic!("Unable to begin frame: frame data is being held by user code")
-            });
+            let frame: &mut DeviceFrame = Arc::get_mut(&mut frame).ok_or(BackendError::FrameInUse)?;
 
+            let command_buffer = &frame.main_command_buffer;
             unsafe {
                 self.raw
                     .wait_for_fences(
-                        &[
-                            frame0.main_command_buffer.submit_done_fence,
-                            frame0.presentation_command_buffer.submit_done_fence,
-                        ], 
-                        true, 
-                        std::u64::MAX,
+                        std::slice::from_ref(&command_buffer.submit_done_fence),
+                        true,
+                        u64::MAX,
                     )
-                    .expect("Wait for fence failed.");
+                    .map_err(|err| self.report_device_lost(err))?;
             }
+            self.graphics_queue.mark_completed(frame.last_submitted);
+            self.collect_garbage()?;
+            // drops from here on may end up in this frame's command buffers
+            self.release_queue.frame_open.store(true, Ordering::Release);
+
+            unsafe {
+                let pools = frame.thread_command_pools.get_mut().unwrap();
+                // a pool its thread didn't use for a whole trip around the frame ring goes,
+                // so threads that come and go don't leave their pools behind
+                pools.retain(|_, pool| {
+                    let idle = pool.is_idle();
+                    if idle {
+                        pool.destroy(&self.raw);
+                    }
+                    !idle
+                });
+                for pool in pools.values_mut() {
+                    pool.reset(&self.raw)?;
+                }
+
+                self.raw
+                    .reset_command_buffer(command_buffer.raw, vk::CommandBufferResetFlags::default())?;
+
+                self.raw
+                    .begin_command_buffer(
+                        command_buffer.raw,
+                        &vk::CommandBufferBeginInfo::builder()
+                            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+                    )?;
+            }
+            self.record_crash_marker(command_buffer.raw, "begin frame");
+
+            *frame.present_semaphore.get_mut().unwrap() = None;
         }
 
-        frame0.clone()
+        Ok(frame.clone())
     }
 
     pub fn with_setup_cb(
         &self,
-        callback: impl FnOnce(vk::CommandBuffer),
+        callback: impl FnOnce(&mut CommandRecorder),
     ) -> Result<()> {
         let cb = self.setup_cb.lock().unwrap();
 
-        unsafe {
-            self.raw
-                .begin_command_buffer(cb.raw, 
-                    &vk::CommandBufferBeginInfo::builder()
-                        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-                    )
-                    .unwrap();
+        let mut recorder = self.begin_recording(&cb)?;
+        callback(&mut recorder);
+        recorder.finish()?;
+
+        // waiting on just this submission leaves the rest of the GPU running
+        if self.timelines_enabled() {
+            let future = self.submit(QueueType::Graphics, std::slice::from_ref(&cb.raw), &[])?;
+            future.wait(self, u64::MAX).map_err(|err| self.report_device_lost(err))?;
+        } else {
+            self.graphics_queue
+                .submit(&SubmitBatch::new(std::slice::from_ref(&cb.raw)))
+                .map_err(|err| self.report_device_lost(err))?;
+
+            // without timelines the queue going idle is the only completion signal
+            self.graphics_queue
+                .wait_idle()
+                .map_err(|err| self.report_device_lost(err))?;
         }
 
-        callback(cb.raw);
+        self.collect_garbage()
+    }
 
-        unsafe {
-            self.raw.end_command_buffer(cb.raw).unwrap();
+    // frees buffers and images dropped since every queue finished the work submitted before
+    // the drop; `begin_frame`, `with_setup_cb` and upload completion do this on their own,
+    // apps that submit through `Device::submit` only should call it now and then
+    pub fn collect_garbage(&self) -> Result<()> {
+        self.release_queue
+            .collect(&self.raw)
+            .map_err(|err| self.report_device_lost(err))
+    }
 
-            let submit_info = 
-                vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(&cb.raw));
+    // submits the frame's command buffer on the graphics queue, signalling its fence; when a
+    // swapchain image was acquired for the frame the submission waits for it and signals the
+    // image's rendering complete semaphore, which `Swapchain::present_image` waits on
+    pub fn finish_frame(&self, frame: Arc<DeviceFrame>) -> Result<()> {
+        drop(frame);
 
+        let slot = self.frame_index.load(Ordering::Acquire) % self.frames.len();
+        let mut frame = self.frames[slot].lock().unwrap();
+        let frame: &mut DeviceFrame = Arc::get_mut(&mut frame).ok_or(BackendError::FrameInUse)?;
+
+        let command_buffer = &frame.main_command_buffer;
+
+        let mut batch = SubmitBatch::new(std::slice::from_ref(&command_buffer.raw))
+            .fence(command_buffer.submit_done_fence);
+        if let Some(present_semaphore) = frame.present_semaphore.get_mut().unwrap().take() {
+            batch = batch
+                .wait(frame.swapchain_acquired_semaphore, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .signal(present_semaphore);
+        }
+
+        self.record_crash_marker(command_buffer.raw, "end frame");
+
+        unsafe {
             self.raw
-                .queue_submit(
-                    self._graphics_queue, 
-                    &[submit_info.build()], 
-                    vk::Fence::null(),
-                )
-                .expect("queue submit failed.");
-
-            //neccessary?
-            self.raw
-                .queue_wait_idle(self._graphics_queue)
-                .expect("Failed to wait Queue idle.");
+                .end_command_buffer(command_buffer.raw)?;
 
-            Ok(self.raw.device_wait_idle()?)
+            self.raw
+                .reset_fences(std::slice::from_ref(&command_buffer.submit_done_fence))?;
         }
-    }
 
-    pub fn finish_frame(&self, frame: Arc<DeviceFrame>) {
-        drop(frame);
-        
-        let mut frame0 = self.frames[0].lock().unwrap();
-        let frame0: &mut DeviceFrame = Arc::get_mut(&mut frame0).unwrap_or_else(|| {
-            panic!("Unable to finish frame: frame data is being held by user code")
-        });
+        let value = match self.graphics_queue.submit(&batch) {
+            std::result::Result::Ok(value) => value,
+            Err(err) => {
+                // nothing is going to signal the reset fence, so the next `begin_frame` on this
+                // slot would wait forever; swap in a signalled one
+                let fence = unsafe {
+                    self.raw
+                        .create_fence(&vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED), None)
+                };
+                if let std::result::Result::Ok(fence) = fence {
+                    let command_buffer = &mut frame.main_command_buffer;
+                    unsafe { self.raw.destroy_fence(command_buffer.submit_done_fence, None) };
+                    command_buffer.submit_done_fence = fence;
+                    self.set_debug_name(fence, &format!("frame {} submit fence", slot));
+                }
+
+                return Err(self.report_device_lost(err));
+            }
+        };
+        frame.last_submitted = value;
 
-        {
-            let mut frame1 = self.frames[1].lock().unwrap();
-            let frame1: &mut DeviceFrame = Arc::get_mut(&mut frame1).unwrap();
+        // anything dropped while recording now waits for the submission above
+        self.release_queue.close_frame();
 
-            //let mut frame2 = self.frames[2].lock();
-            //let frame2: &mut DeviceFrame = Arc::get_mut(&mut frame2).unwrap();
+        self.frame_index.fetch_add(1, Ordering::AcqRel);
 
-            std::mem::swap(frame0, frame1);
-            //std::mem::swap(frame1, frame2);
-        }
+        Ok(())
     }
 
-    pub fn create_descriptor_set_layout(&self) -> vk::DescriptorSetLayout {
+    pub fn create_descriptor_set_layout(&self) -> Result<vk::DescriptorSetLayout> {
         let ubo_layout_bindings = [
         vk::DescriptorSetLayoutBinding {
             // transform uniform
@@ -275,17 +585,13 @@ impl Device {
             p_bindings: ubo_layout_bindings.as_ptr(),
         };
 
-        unsafe {
-            self.raw
-                .create_descriptor_set_layout(&ubo_layout_create_info, None)
-                .expect("Failed to create Descriptor Set Layout!")
-        }
+        Ok(unsafe { self.raw.create_descriptor_set_layout(&ubo_layout_create_info, None)? })
     }
 
     pub fn create_descriptor_pool(
         &self,
         swapchain_images_size: usize,
-    ) -> vk::DescriptorPool {
+    ) -> Result<vk::DescriptorPool> {
         let pool_sizes = [
             // transform descriptor pool
             vk::DescriptorPoolSize {
@@ -308,22 +614,20 @@ impl Device {
             p_pool_sizes: pool_sizes.as_ptr(),
         };
 
-        unsafe {
-            self.raw
-                .create_descriptor_pool(&descriptor_pool_create_info, None)
-                .expect("Failed to create Descriptor Pool!")
-        }
+        Ok(unsafe { self.raw.create_descriptor_pool(&descriptor_pool_create_info, None)? })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_descriptor_sets(
         &self,
         descriptor_pool: vk::DescriptorPool,
         descriptor_set_layout: vk::DescriptorSetLayout,
-        uniform_buffers: &Vec<buffer::Buffer>,
+        uniform_buffers: &[buffer::Buffer],
         texture_image_view: vk::ImageView,
         texture_sampler: vk::Sampler,
         swapchain_images_size: usize,
-    ) -> Result<Vec<vk::DescriptorSet>> {
+        name: Option<&str>,
+    ) -> Result<Vec<DescriptorSet>> {
         let mut layouts: Vec<vk::DescriptorSetLayout> = vec![];
         for _ in 0..swapchain_images_size {
             layouts.push(descriptor_set_layout);
@@ -339,11 +643,14 @@ impl Device {
 
         let descriptor_sets = unsafe {
             self.raw
-                .allocate_descriptor_sets(&descriptor_set_allocate_info)
-                .expect("Failed to allocate descriptor sets!")
+                .allocate_descriptor_sets(&descriptor_set_allocate_info)?
         };
 
         for (i, &descriptor_set) in descriptor_sets.iter().enumerate() {
+            if let Some(name) = name {
+                self.set_debug_name(descriptor_set, &format!("{} {}", name, i));
+            }
+
             let descriptor_buffer_info = [vk::DescriptorBufferInfo {
                 buffer: uniform_buffers[i].raw,
                 offset: 0,
@@ -388,18 +695,105 @@ impl Device {
             }
         }
 
-        Ok(descriptor_sets)
+        Ok(descriptor_sets
+            .into_iter()
+            .map(|raw| DescriptorSet { raw })
+            .collect())
+    }
+}
+
+// a descriptor set from `create_descriptor_sets`, freed with its pool
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct DescriptorSet {
+    pub(crate) raw: vk::DescriptorSet,
+}
+
+impl DescriptorSet {
+    pub fn raw(&self) -> vk::DescriptorSet {
+        self.raw
+    }
+}
+
+// closes its label on drop, must not outlive the command buffer recording
+pub struct DebugLabelScope<'a> {
+    device: &'a Device,
+    command_buffer: vk::CommandBuffer,
+    active: bool,
+}
+
+impl Drop for DebugLabelScope<'_> {
+    fn drop(&mut self) {
+        if self.active {
+            unsafe {
+                self.device
+                    .instance
+                    .debug_utils
+                    .cmd_end_debug_utils_label(self.command_buffer);
+            }
+        }
     }
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
+        // frames own fences, semaphores and pools that have to go before the device; one still
+        // held by the app destroys them once it's let go, so the device is leaked to outlive it
+        let frame_held = self
+            .frames
+            .iter_mut()
+            .any(|frame| Arc::get_mut(frame.get_mut().unwrap_or_else(PoisonError::into_inner)).is_none());
+
         unsafe {
+            let _ = self.raw.device_wait_idle();
+            self.release_queue.release_all(&self.raw);
+            self.frames.clear();
+            ManuallyDrop::drop(&mut self.setup_cb);
+
+            if let Some(crash_tracker) = self.crash_tracker.take() {
+                crash_tracker.destroy(self);
+            }
+
+            // aliased queues share one timeline, destroy its semaphore once; the present queue
+            // has one too when it has its own handle
+            self.timelines.clear();
+            let timeline_semaphores = [&self.graphics_queue, &self.compute_queue, &self.transfer_queue]
+                .into_iter()
+                .chain(self.present_queue.as_ref())
+                .filter_map(|queue| queue.timeline)
+                .collect::<std::collections::HashSet<_>>();
+            for semaphore in timeline_semaphores {
+                self.raw.destroy_semaphore(semaphore, None);
+            }
+
+            if frame_held {
+                log::error!("Device dropped while a DeviceFrame from begin_frame is still alive, leaking the VkDevice");
+                return;
+            }
             self.raw.destroy_device(None);
         }
     }
 }
 
+// destroys the raw device and the queues' timeline semaphores when `Device::create` bails before
+// `Device` exists; frames and the setup command buffer are declared after it, so they go first
+struct CreateGuard {
+    device: Option<ash::Device>,
+    queues: Vec<Arc<Queue>>,
+}
+
+impl Drop for CreateGuard {
+    fn drop(&mut self) {
+        if let Some(device) = self.device.take() {
+            unsafe {
+                for semaphore in self.queues.iter().filter_map(|queue| queue.timeline) {
+                    device.destroy_semaphore(semaphore, None);
+                }
+                device.destroy_device(None);
+            }
+        }
+    }
+}
+
 pub struct CommandBuffer {
     pub raw: vk::CommandBuffer,
     pub submit_done_fence: vk::Fence,
@@ -417,7 +811,7 @@ impl CommandBuffer {
         .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
         .queue_family_index(queue_family_index);
 
-        let pool = unsafe { device.create_command_pool(&pool_create_info, None).unwrap() };
+        let pool = unsafe { device.create_command_pool(&pool_create_info, None)? };
 
         let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
             .command_buffer_count(1)
@@ -427,7 +821,7 @@ impl CommandBuffer {
         let cb = unsafe {
             device
                 .allocate_command_buffers(&command_buffer_allocate_info)
-                .unwrap()
+                .inspect_err(|_| device.destroy_command_pool(pool, None))?
         }[0];
 
         let submit_done_fence = unsafe {
@@ -461,27 +855,163 @@ impl Drop for CommandBuffer {
 }
 
 pub struct DeviceFrame {
-    pub swapchain_acquired_semaphore: Option<vk::Semaphore>,
-    pub rendering_complete_semaphore: Option<vk::Semaphore>,
+    pub swapchain_acquired_semaphore: vk::Semaphore,
+    // its `submit_done_fence` is the frame fence
     pub main_command_buffer: CommandBuffer,
-    pub presentation_command_buffer: CommandBuffer,
-    //pub pending_resource_releases: Mutex<PendingResourceReleases>,
+    // set by `Swapchain::acquire_next_image` to the acquired image's rendering complete
+    // semaphore; those live per image, a frame slot can come round again before the
+    // presentation waiting on its last one is done
+    pub(crate) present_semaphore: Mutex<Option<vk::Semaphore>>,
+    logical_device: ash::Device,
+    // graphics queue value of the slot's last submission, done once its fence has signalled
+    pub(crate) last_submitted: u64,
+    // secondaries and extra primaries recorded for this frame, keyed by recording thread
+    pub(crate) thread_command_pools: Mutex<ThreadCommandPools>,
     //pub profiler_data: VkProfilerData,
 }
 
 impl DeviceFrame {
     pub fn new(
-        physical_device: &Arc<PhysicalDevice>,
+        _physical_device: &Arc<PhysicalDevice>,
         logical_device: &ash::Device,
         //global_allocator: &mut VulkanAllocator,
         queue_family: &QueueFamilyIndices,
-    ) -> DeviceFrame {
+    ) -> Result<DeviceFrame> {
+        let create_semaphore = || unsafe {
+            logical_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+        };
+
+        Ok(DeviceFrame {
+            swapchain_acquired_semaphore: create_semaphore()?,
+            main_command_buffer: CommandBuffer::create(logical_device, queue_family.graphics_family.unwrap())?,
+            present_semaphore: Mutex::new(None),
+            logical_device: logical_device.clone(),
+            last_submitted: 0,
+            thread_command_pools: Default::default(),
+        })
+    }
+}
+
+impl Drop for DeviceFrame {
+    fn drop(&mut self) {
+        unsafe {
+            self.logical_device.destroy_semaphore(self.swapchain_acquired_semaphore, None);
+            for pool in self.thread_command_pools.get_mut().unwrap().values_mut() {
+                pool.destroy(&self.logical_device);
+            }
+        }
+    }
+}
+
+// Vulkan handles whose owners were dropped while the GPU may still be using them
+#[derive(Default)]
+pub struct PendingResourceReleases {
+    pub image_views: Vec<vk::ImageView>,
+    pub images: Vec<vk::Image>,
+    pub buffers: Vec<vk::Buffer>,
+    pub memory: Vec<vk::DeviceMemory>,
+}
+
+impl PendingResourceReleases {
+    pub fn is_empty(&self) -> bool {
+        self.image_views.is_empty()
+            && self.images.is_empty()
+            && self.buffers.is_empty()
+            && self.memory.is_empty()
+    }
+
+    // the caller guarantees the GPU is done with all of it
+    unsafe fn release_all(&mut self, device: &ash::Device) {
+        for view in self.image_views.drain(..) {
+            device.destroy_image_view(view, None);
+        }
+        for image in self.images.drain(..) {
+            device.destroy_image(image, None);
+        }
+        for buffer in self.buffers.drain(..) {
+            device.destroy_buffer(buffer, None);
+        }
+        for memory in self.memory.drain(..) {
+            device.free_memory(memory, None);
+        }
+    }
+}
+
+// shared between the device and the resources it creates, so their `Drop` can queue handles;
+// a release waits until every queue has finished what was submitted to it before the drop
+pub struct ReleaseQueue {
+    // one per queue handle
+    queues: Vec<Arc<Queue>>,
+    // between `begin_frame` and `finish_frame`, when a drop may still be recorded into the frame
+    frame_open: AtomicBool,
+    // dropped while the frame was open, tagged once it is submitted
+    frame_releases: Mutex<PendingResourceReleases>,
+    // with the `Queue::last_submitted` value of each of `queues` to wait for
+    tagged: Mutex<VecDeque<(Vec<u64>, PendingResourceReleases)>>,
+}
+
+impl ReleaseQueue {
+    fn new(queues: Vec<Arc<Queue>>) -> ReleaseQueue {
+        ReleaseQueue {
+            queues,
+            frame_open: AtomicBool::new(false),
+            frame_releases: Default::default(),
+            tagged: Default::default(),
+        }
+    }
 
-        DeviceFrame {
-            swapchain_acquired_semaphore: None,
-            rendering_complete_semaphore: None,
-            main_command_buffer: CommandBuffer::create(logical_device, queue_family.graphics_family.unwrap()).unwrap(),
-            presentation_command_buffer: CommandBuffer::create(logical_device, queue_family.graphics_family.unwrap()).unwrap(),
+    pub(crate) fn defer(&self, release: impl FnOnce(&mut PendingResourceReleases)) {
+        if self.frame_open.load(Ordering::Acquire) {
+            release(&mut self.frame_releases.lock().unwrap());
+        } else {
+            let mut releases = PendingResourceReleases::default();
+            release(&mut releases);
+            self.push_tagged(releases);
         }
     }
-}
\ No newline at end of file
+
+    fn push_tagged(&self, releases: PendingResourceReleases) {
+        if releases.is_empty() {
+            return;
+        }
+
+        let tag = self.queues.iter().map(|queue| queue.last_submitted()).collect();
+        self.tagged.lock().unwrap().push_back((tag, releases));
+    }
+
+    fn close_frame(&self) {
+        self.frame_open.store(false, Ordering::Release);
+        self.push_tagged(std::mem::take(&mut self.frame_releases.lock().unwrap()));
+    }
+
+    fn collect(&self, device: &ash::Device) -> Result<()> {
+        // a drop racing `close_frame` can land in `frame_releases` late, don't strand it there
+        if !self.frame_open.load(Ordering::Acquire) {
+            self.push_tagged(std::mem::take(&mut self.frame_releases.lock().unwrap()));
+        }
+
+        let completed = self
+            .queues
+            .iter()
+            .map(|queue| queue.completed())
+            .collect::<Result<Vec<_>>>()?;
+
+        self.tagged.lock().unwrap().retain_mut(|(tag, releases)| {
+            let done = tag.iter().zip(completed.iter()).all(|(tag, completed)| tag <= completed);
+            if done {
+                unsafe { releases.release_all(device) };
+            }
+            !done
+        });
+
+        Ok(())
+    }
+
+    // the caller guarantees the device is idle
+    unsafe fn release_all(&self, device: &ash::Device) {
+        self.frame_releases.lock().unwrap().release_all(device);
+        for (_, mut releases) in self.tagged.lock().unwrap().drain(..) {
+            releases.release_all(device);
+        }
+    }
+}
@@ -6,27 +6,55 @@ pub mod swapchain;
 pub mod image;
 pub mod shader;
 pub mod buffer;
+pub mod features;
+pub mod timeline;
+pub mod upload;
+pub mod command;
+pub mod error;
+pub mod crash;
+pub mod queue;
 
 use std::sync::Arc;
 
 use anyhow;
-use anyhow::Ok;
+
+use ash::vk;
 
 use crate::window;
+use crate::debug;
 use crate::constants;
 
 pub struct RenderBackend {
+    // fields drop in declaration order: everything built on the instance goes first,
+    // and the window has to outlive the surface
+    pub swapchain: Option<swapchain::Swapchain>,
+    // `None` when the backend was created headless
+    pub surface: Option<Arc<surface::Surface>>,
     pub device: Arc<logical_device::Device>,
-    pub surface: Arc<surface::Surface>,
-    pub swapchain: swapchain::Swapchain,
+    pub physical_device: Arc<physical_device::PhysicalDevice>,
+    pub instance: Arc<instance::Instance>,
+    pub window: Option<winit::window::Window>,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct RenderBackendConfig {
     pub swapchain_extent: [u32; 2],
     pub vsync: bool,
     pub graphics_debugging: bool,
+    // only used with `graphics_debugging`
+    pub validation_features: debug::ValidationFeatures,
     pub device_index: Option<usize>,
+    // substring of the device name, takes precedence over `device_index`;
+    // both are overridden by the CRANBERRIES_DEVICE environment variable
+    pub device_name: Option<String>,
+    // devices missing a required feature are skipped during selection
+    pub device_features: features::DeviceFeatureRequest,
+    // on top of VK_KHR_swapchain, which windowed backends always require
+    pub device_extensions: debug::DeviceExtension,
+    // size of the `Device::begin_frame` ring, `constants::MAX_FRAMES_IN_FLIGHT` is a good default
+    pub frames_in_flight: usize,
+    // GPU breadcrumbs, see `Device::crash_report`; costs a barrier per marker without VK_AMD_buffer_marker
+    pub crash_tracking: bool,
 }
 
 impl RenderBackend {
@@ -34,28 +62,100 @@ impl RenderBackend {
         event_loop: &winit::event_loop::EventLoop<()>,
         config: RenderBackendConfig,
     ) -> anyhow::Result<RenderBackend> {
+        RenderBackend::with_instance_desc(event_loop, config, instance::InstanceDesc::windowed())
+    }
 
-        let window = window::init_window(&event_loop, constants::WINDOW_TITLE, constants::WINDOW_WIDTH, constants::WINDOW_HEIGHT);
+    // for apps that need their own app name, layers or debug messenger sink;
+    // `config.graphics_debugging` and `config.validation_features` still win over the desc
+    pub fn with_instance_desc(
+        event_loop: &winit::event_loop::EventLoop<()>,
+        config: RenderBackendConfig,
+        instance_desc: instance::InstanceDesc,
+    ) -> anyhow::Result<RenderBackend> {
+
+        let window = window::init_window(event_loop, constants::WINDOW_TITLE, config.swapchain_extent[0], config.swapchain_extent[1]);
         
         let instance = 
-            Arc::new(instance::Instance::new().unwrap());
+            Arc::new(instance::Instance::create(
+                instance_desc
+                    .graphics_debugging(config.graphics_debugging)
+                    .validation_features(config.validation_features),
+            )?);
         
         let surface =
-            Arc::new(surface::Surface::create(&instance, &window).unwrap());
+            Arc::new(surface::Surface::create(&instance, &window)?);
+
+        let device_extensions = debug::DeviceExtension::windowed().extend(&config.device_extensions);
         
         let physical_device = 
-            Arc::new(physical_device::PhysicalDevice::create(&instance, &surface).unwrap());
+            Arc::new(physical_device::PhysicalDevice::create(&instance, Some(&surface), &RenderBackend::device_selection(&config), &config.device_features, &device_extensions)?);
         
         let device = 
-            Arc::new(logical_device::Device::create(&instance, &physical_device, &surface,&constants::VALIDATION, &constants::DEVICE_EXTENSIONS).unwrap());
+            Arc::new(logical_device::Device::create(&instance, &physical_device, &RenderBackend::validation_info(&instance), &device_extensions, &config.device_features, config.frames_in_flight, config.crash_tracking)?);
+
+        let swapchain_desc = swapchain::SwapchainDesc {
+            dims: vk::Extent2D {
+                width: config.swapchain_extent[0],
+                height: config.swapchain_extent[1],
+            },
+            vsync: config.vsync,
+            ..Default::default()
+        };
+        let swapchain = swapchain::Swapchain::create(&instance, &device, &physical_device, &surface, &physical_device.queue_family_index, swapchain_desc)?;
+
+        anyhow::Ok(RenderBackend {
+            swapchain: Some(swapchain),
+            surface: Some(surface),
+            device,
+            physical_device,
+            instance,
+            window: Some(window),
+        })
+    }
 
-        let swapchain_desc: swapchain::SwapchainDesc = Default::default();
-        let swapchain = swapchain::Swapchain::create(&instance, &device, &physical_device, &surface, &physical_device.queue_family_index, swapchain_desc).unwrap();
+    // no window, surface or swapchain: render into images from `Device::create_offscreen_image`
+    pub fn new_headless(
+        config: RenderBackendConfig,
+    ) -> anyhow::Result<RenderBackend> {
+        RenderBackend::headless_with_instance_desc(config, instance::InstanceDesc::headless())
+    }
+
+    pub fn headless_with_instance_desc(
+        config: RenderBackendConfig,
+        instance_desc: instance::InstanceDesc,
+    ) -> anyhow::Result<RenderBackend> {
+        let instance =
+            Arc::new(instance::Instance::create(
+                instance_desc
+                    .graphics_debugging(config.graphics_debugging)
+                    .validation_features(config.validation_features),
+            )?);
+
+        let physical_device =
+            Arc::new(physical_device::PhysicalDevice::create(&instance, None, &RenderBackend::device_selection(&config), &config.device_features, &config.device_extensions)?);
+
+        let device =
+            Arc::new(logical_device::Device::create(&instance, &physical_device, &RenderBackend::validation_info(&instance), &config.device_extensions, &config.device_features, config.frames_in_flight, config.crash_tracking)?);
 
         anyhow::Ok(RenderBackend {
+            swapchain: None,
+            surface: None,
             device,
-            surface,
-            swapchain,
+            physical_device,
+            instance,
+            window: None,
         })
     }
-}
\ No newline at end of file
+
+    fn device_selection(config: &RenderBackendConfig) -> physical_device::DeviceSelection {
+        physical_device::DeviceSelection::from_config(config.device_index, config.device_name.as_deref()).or_env()
+    }
+
+    // the validation layer may have been skipped if it isn't installed
+    fn validation_info(instance: &instance::Instance) -> debug::ValidationInfo {
+        debug::ValidationInfo {
+            is_enable: instance.validation_enabled(),
+            ..constants::VALIDATION
+        }
+    }
+}
@@ -2,20 +2,32 @@ use anyhow::{Result, Ok};
 use crate::vulkan::instance::Instance;
 use crate::tools;
 use crate::vulkan::surface::Surface;
-use crate::constants;
 use crate::vulkan::swapchain::Swapchain;
 use crate::debug;
+use crate::vulkan::error::BackendError;
 
-use ash::vk::{self, Queue};
+use ash::vk;
 //use ash::{vk_version_major, vk_version_minor, vk_version_patch};
 
-use std::sync::Arc;
-use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::ffi::CStr;
+
+use super::features::{DeviceFeatureRequest, PhysicalDeviceDynamicRenderingFeaturesKHR, DYNAMIC_RENDERING_EXTENSION_NAME};
 
 pub struct QueueFamilyIndices {
     pub graphics_family: Option<u32>,
     pub present_family: Option<u32>,
+    // a compute family without graphics when the device has one, otherwise the graphics family
+    pub compute_family: Option<u32>,
+    // a transfer-only family (usually the DMA engine) when available, otherwise the graphics family
+    pub transfer_family: Option<u32>,
+}
 
+impl Default for QueueFamilyIndices {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl QueueFamilyIndices {
@@ -23,279 +35,573 @@ impl QueueFamilyIndices {
         QueueFamilyIndices {
             graphics_family: None,
             present_family: None,
+            compute_family: None,
+            transfer_family: None,
         }
     }
 
-    pub fn is_complete(&self) -> bool {
-        self.graphics_family.is_some() && self.present_family.is_some()
+    pub fn has_dedicated_compute(&self) -> bool {
+        self.compute_family.is_some() && self.compute_family != self.graphics_family
+    }
+
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.transfer_family.is_some() && self.transfer_family != self.graphics_family
+    }
+
+    pub fn is_complete(&self, presentation_requested: bool) -> bool {
+        self.graphics_family.is_some()
+            && (!presentation_requested || self.present_family.is_some())
     }
 }
-//to do : finish it
+// core, 1.1 and 1.2 features as reported by the device, zeroed where the version is missing;
+// extension features stay zeroed when the extension isn't available
+#[derive(Clone, Copy, Default)]
+pub struct DeviceFeatures {
+    pub core: vk::PhysicalDeviceFeatures,
+    pub vulkan_11: vk::PhysicalDeviceVulkan11Features,
+    pub vulkan_12: vk::PhysicalDeviceVulkan12Features,
+    pub synchronization2: vk::PhysicalDeviceSynchronization2FeaturesKHR,
+    pub dynamic_rendering: PhysicalDeviceDynamicRenderingFeaturesKHR,
+}
+
+// the p_next pointers are cleared after the query, so this is plain data
+unsafe impl Send for DeviceFeatures {}
+unsafe impl Sync for DeviceFeatures {}
+
+impl DeviceFeatures {
+    fn query(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        properties: &vk::PhysicalDeviceProperties,
+        extensions: &[String],
+    ) -> DeviceFeatures {
+        let mut features = DeviceFeatures::default();
+
+        if DeviceFeatures::has_features2(instance, properties) {
+            let has_extension = |name: &CStr| extensions.iter().any(|extension| name.to_str().is_ok_and(|name| name == extension));
+
+            let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+                .push_next(&mut features.vulkan_11)
+                .push_next(&mut features.vulkan_12);
+            // only chain structs the driver knows about
+            if has_extension(vk::KhrSynchronization2Fn::name()) {
+                features2 = features2.push_next(&mut features.synchronization2);
+            }
+            if has_extension(DYNAMIC_RENDERING_EXTENSION_NAME) {
+                features2 = features2.push_next(&mut features.dynamic_rendering);
+            }
+            let mut features2 = features2.build();
+            unsafe {
+                instance
+                    .raw
+                    .get_physical_device_features2(physical_device, &mut features2)
+            };
+            features.core = features2.features;
+        } else {
+            features.core = unsafe { instance.raw.get_physical_device_features(physical_device) };
+        }
+
+        features.clear_chain();
+        features
+    }
+
+    // the 1.1 / 1.2 structs can only be chained on 1.2 instances and devices
+    pub(crate) fn has_features2(instance: &Instance, properties: &vk::PhysicalDeviceProperties) -> bool {
+        let vulkan_1_2 = vk::make_api_version(0, 1, 2, 0);
+        instance.api_version() >= vulkan_1_2 && properties.api_version >= vulkan_1_2
+    }
+
+    pub(crate) fn clear_chain(&mut self) {
+        self.vulkan_11.p_next = std::ptr::null_mut();
+        self.vulkan_12.p_next = std::ptr::null_mut();
+        self.synchronization2.p_next = std::ptr::null_mut();
+        self.dynamic_rendering.p_next = std::ptr::null_mut();
+    }
+}
+
 pub struct PhysicalDevice {
     pub instance: Arc<Instance>,
     pub raw: vk::PhysicalDevice,
     pub queue_family_index: QueueFamilyIndices,
-    //pub(crate) queue_families: Vec<QueueFamily>,
-    /* 
     pub(crate) presentation_requested: bool,
-    pub properties: PhysicalDeviceProperties,
-    pub memory_properties: PhysicalDeviceMemoryProperties,
-    */
+    // queried once at creation
+    pub(crate) properties: vk::PhysicalDeviceProperties,
+    pub(crate) features: DeviceFeatures,
+    pub(crate) memory_properties: vk::PhysicalDeviceMemoryProperties,
+    pub(crate) queue_families: Vec<vk::QueueFamilyProperties>,
+    pub(crate) extensions: Vec<String>,
+    // filled lazily by `format_properties`
+    pub(crate) format_properties: Mutex<HashMap<vk::Format, vk::FormatProperties>>,
+}
+
+// which GPU to use, ranked best-first when not forced
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceSelection {
+    #[default]
+    Best,
+    Index(usize),
+    // case-insensitive substring of the device name, e.g. "nvidia" or "llvmpipe"
+    Name(String),
+}
+
+impl DeviceSelection {
+    // an index or a name substring, lets users override the app's choice
+    pub const ENV_VAR: &'static str = "CRANBERRIES_DEVICE";
+
+    pub fn from_config(device_index: Option<usize>, device_name: Option<&str>) -> Self {
+        match (device_name, device_index) {
+            (Some(name), _) => DeviceSelection::Name(name.to_owned()),
+            (None, Some(index)) => DeviceSelection::Index(index),
+            (None, None) => DeviceSelection::Best,
+        }
+    }
+
+    pub fn or_env(self) -> Self {
+        self.or_value(std::env::var(Self::ENV_VAR).ok().as_deref())
+    }
+
+    fn or_value(self, value: Option<&str>) -> Self {
+        match value.map(str::trim) {
+            Some(value) if !value.is_empty() => match value.parse::<usize>() {
+                std::result::Result::Ok(index) => DeviceSelection::Index(index),
+                Err(_) => DeviceSelection::Name(value.to_owned()),
+            },
+            _ => self,
+        }
+    }
+
+    // why the device at `index` isn't the selected one, if it isn't
+    fn rejects(&self, index: usize, device_name: &str) -> Option<String> {
+        match self {
+            DeviceSelection::Index(selected) if *selected != index => {
+                Some(format!("not the selected device index {}", selected))
+            }
+            DeviceSelection::Name(name) if !device_name.to_lowercase().contains(&name.to_lowercase()) => {
+                Some(format!("name doesn't contain \"{}\"", name))
+            }
+            _ => None,
+        }
+    }
 }
 
 impl PhysicalDevice {
+    // pass `None` as surface to pick a device for headless (offscreen) rendering
     pub fn create(
         instance: &Arc<Instance>,
-        surface: &Surface,
+        surface: Option<&Surface>,
+        selection: &DeviceSelection,
+        features: &DeviceFeatureRequest,
+        extensions: &debug::DeviceExtension,
     ) -> Result<PhysicalDevice> {
-        let physical_device = PhysicalDevice::pick_physical_device(&instance.raw, &surface, &constants::DEVICE_EXTENSIONS).unwrap();
+        let physical_device = PhysicalDevice::pick_physical_device(instance, surface, extensions, selection, features)?;
+
+        Ok(PhysicalDevice::from_raw(instance, physical_device, surface))
+    }
+
+    // every device the instance can see, suitable or not, e.g. for reporting tools
+    pub fn enumerate(
+        instance: &Arc<Instance>,
+        surface: Option<&Surface>,
+    ) -> Result<Vec<PhysicalDevice>> {
+        let physical_devices = unsafe { instance.raw.enumerate_physical_devices()? };
+
+        Ok(physical_devices
+            .into_iter()
+            .map(|physical_device| PhysicalDevice::from_raw(instance, physical_device, surface))
+            .collect())
+    }
+
+    fn from_raw(
+        instance: &Arc<Instance>,
+        physical_device: vk::PhysicalDevice,
+        surface: Option<&Surface>,
+    ) -> PhysicalDevice {
         let queue_family_index = PhysicalDevice::find_queue_family(&instance.raw, physical_device, surface);
+
+        let properties = unsafe { instance.raw.get_physical_device_properties(physical_device) };
+        let extensions = PhysicalDevice::query_extensions(&instance.raw, physical_device);
+        let features = DeviceFeatures::query(instance, physical_device, &properties, &extensions);
+        let memory_properties = unsafe { instance.raw.get_physical_device_memory_properties(physical_device) };
+        let queue_families = unsafe { instance.raw.get_physical_device_queue_family_properties(physical_device) };
         
-        Ok(PhysicalDevice {
+        PhysicalDevice {
             instance: instance.clone(),
             raw: physical_device,
             queue_family_index,
-        })
+            presentation_requested: surface.is_some(),
+            properties,
+            features,
+            memory_properties,
+            queue_families,
+            extensions,
+            format_properties: Default::default(),
+        }
     }
 
-    fn pick_physical_device(
+    fn query_extensions(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Vec<String> {
+        unsafe { instance.enumerate_device_extension_properties(physical_device) }
+            .unwrap_or_default()
+            .iter()
+            .map(|extension| tools::vk_to_string(&extension.extension_name))
+            .collect()
+    }
+
+    pub fn presentation_requested(&self) -> bool {
+        self.presentation_requested
+    }
+
+    pub fn properties(&self) -> &vk::PhysicalDeviceProperties {
+        &self.properties
+    }
+
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.properties.limits
+    }
+
+    pub fn device_name(&self) -> String {
+        tools::vk_to_string(&self.properties.device_name)
+    }
+
+    pub fn device_type(&self) -> vk::PhysicalDeviceType {
+        self.properties.device_type
+    }
+
+    pub fn api_version(&self) -> u32 {
+        self.properties.api_version
+    }
+
+    pub fn features(&self) -> &vk::PhysicalDeviceFeatures {
+        &self.features.core
+    }
+
+    pub fn features_11(&self) -> &vk::PhysicalDeviceVulkan11Features {
+        &self.features.vulkan_11
+    }
+
+    pub fn features_12(&self) -> &vk::PhysicalDeviceVulkan12Features {
+        &self.features.vulkan_12
+    }
+
+    // everything at once, e.g. for `DeviceFeatureRequest::resolve`
+    pub fn supported_features(&self) -> &DeviceFeatures {
+        &self.features
+    }
+
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|extension| extension == name)
+    }
+
+    pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+        &self.memory_properties
+    }
+
+    pub fn memory_heaps(&self) -> &[vk::MemoryHeap] {
+        &self.memory_properties.memory_heaps[..self.memory_properties.memory_heap_count as usize]
+    }
+
+    pub fn memory_types(&self) -> &[vk::MemoryType] {
+        &self.memory_properties.memory_types[..self.memory_properties.memory_type_count as usize]
+    }
+
+    pub fn queue_families(&self) -> &[vk::QueueFamilyProperties] {
+        &self.queue_families
+    }
+
+    pub fn format_properties(&self, format: vk::Format) -> vk::FormatProperties {
+        *self
+            .format_properties
+            .lock()
+            .unwrap()
+            .entry(format)
+            .or_insert_with(|| unsafe {
+                self.instance
+                    .raw
+                    .get_physical_device_format_properties(self.raw, format)
+            })
+    }
+
+    pub fn supports_format(
+        &self,
+        format: vk::Format,
+        tiling: vk::ImageTiling,
+        features: vk::FormatFeatureFlags,
+    ) -> bool {
+        let properties = self.format_properties(format);
+        match tiling {
+            vk::ImageTiling::LINEAR => properties.linear_tiling_features.contains(features),
+            vk::ImageTiling::OPTIMAL => properties.optimal_tiling_features.contains(features),
+            _ => false,
+        }
+    }
+
+    // the first candidate that supports `features`, so order candidates by preference
+    pub fn find_supported_format(
+        &self,
+        candidates: &[vk::Format],
+        tiling: vk::ImageTiling,
+        features: vk::FormatFeatureFlags,
+    ) -> Result<vk::Format> {
+        candidates
+            .iter()
+            .copied()
+            .find(|&format| self.supports_format(format, tiling, features))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "None of the formats {:?} support {:?} with {:?} tiling",
+                    candidates,
+                    features,
+                    tiling
+                )
+            })
+    }
+
+    pub fn best_depth_format(&self, need_stencil: bool) -> Result<vk::Format> {
+        let candidates: &[vk::Format] = if need_stencil {
+            &[
+                vk::Format::D24_UNORM_S8_UINT,
+                vk::Format::D32_SFLOAT_S8_UINT,
+                vk::Format::D16_UNORM_S8_UINT,
+            ]
+        } else {
+            &[
+                vk::Format::D32_SFLOAT,
+                vk::Format::D32_SFLOAT_S8_UINT,
+                vk::Format::D24_UNORM_S8_UINT,
+                vk::Format::D16_UNORM,
+            ]
+        };
+
+        self.find_supported_format(
+            candidates,
+            vk::ImageTiling::OPTIMAL,
+            vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+        )
+    }
+
+    // both directions, as needed for generating mips
+    pub fn supports_blit(&self, format: vk::Format) -> bool {
+        self.supports_format(
+            format,
+            vk::ImageTiling::OPTIMAL,
+            vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST,
+        )
+    }
+
+    pub fn supports_linear_filter(&self, format: vk::Format, tiling: vk::ImageTiling) -> bool {
+        self.supports_format(format, tiling, vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+    }
+
+    pub fn supports_storage(&self, format: vk::Format) -> bool {
+        self.supports_format(format, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::STORAGE_IMAGE)
+    }
+
+    // discrete > integrated > virtual > CPU, ties broken by device-local memory
+    fn device_rank(
         instance: &ash::Instance,
-        surface: &Surface,
+        physical_device: vk::PhysicalDevice,
+    ) -> (u32, vk::DeviceSize) {
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+
+        PhysicalDevice::rank(properties.device_type, &memory_properties)
+    }
+
+    fn rank(
+        device_type: vk::PhysicalDeviceType,
+        memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    ) -> (u32, vk::DeviceSize) {
+        let type_rank = match device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+
+        let device_local_memory = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+
+        (type_rank, device_local_memory)
+    }
+
+    fn pick_physical_device(
+        instance: &Instance,
+        surface: Option<&Surface>,
         required_device_extensions: &debug::DeviceExtension,
+        selection: &DeviceSelection,
+        features: &DeviceFeatureRequest,
     ) -> Result<vk::PhysicalDevice> {
         let physical_devices = unsafe {
             instance
-                .enumerate_physical_devices()
-                .expect("Failed to enumerate Physical Devices!")
+                .raw
+                .enumerate_physical_devices()?
         };
 
-        println!(
+        log::info!(
             "{} devices (GPU) found with vulkan support.",
             physical_devices.len()
         );
 
-        let result = physical_devices.iter().find(|physical_device| {
-            let is_suitable = PhysicalDevice::is_physical_device_suitable(
-                instance,
-                **physical_device,
-                surface,
-                required_device_extensions,
-            );
-    
-            // if is_suitable {
-            //     let device_properties = instance.get_physical_device_properties(**physical_device);
-            //     let device_name = super::tools::vk_to_string(&device_properties.device_name);
-            //     println!("Using GPU: {}", device_name);
-            // }
-    
-            is_suitable
-        });
-
-        match result {
-            Some(p_physical_device) => Ok(*p_physical_device),
-            None => panic!("Failed to find a suitable GPU!"),
+        if let DeviceSelection::Index(index) = selection {
+            if *index >= physical_devices.len() {
+                anyhow::bail!(BackendError::InvalidArgument(format!(
+                    "device index {} is out of range, only {} devices found",
+                    index,
+                    physical_devices.len()
+                )));
+            }
         }
-    }
 
-    fn is_physical_device_suitable(
-        instance: &ash::Instance,
-        physical_device: vk::PhysicalDevice,
-        surface: &Surface,
-        required_device_extensions: &debug::DeviceExtension,
-    ) -> bool {
-        let device_properties = 
-            unsafe { instance.get_physical_device_properties(physical_device) };
-        let device_features = 
-            unsafe { instance.get_physical_device_features(physical_device) };
-        let device_queue_families =
-            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let mut candidates = vec![];
+        let mut rejected = vec![];
+        for (index, &physical_device) in physical_devices.iter().enumerate() {
+            let properties = unsafe { instance.raw.get_physical_device_properties(physical_device) };
+            let device_name = tools::vk_to_string(&properties.device_name);
+
+            let mut reasons: Vec<String> = selection.rejects(index, &device_name).into_iter().collect();
+
+            if reasons.is_empty() {
+                reasons = PhysicalDevice::unsuitable_reasons(
+                    instance,
+                    physical_device,
+                    surface,
+                    required_device_extensions,
+                    features,
+                );
+            }
 
-        
-        // let device_type = match device_properties.device_type {
-        //     vk::PhysicalDeviceType::CPU => "Cpu",
-        //     vk::PhysicalDeviceType::INTEGRATED_GPU => "Integrated GPU",
-        //     vk::PhysicalDeviceType::DISCRETE_GPU => "Discrete GPU",
-        //     vk::PhysicalDeviceType::VIRTUAL_GPU => "Virtual GPU",
-        //     vk::PhysicalDeviceType::OTHER => "Unknown",
-        //     _ => panic!(),
-        // };
-
-        // let device_name = tools::vk_to_string(&device_properties.device_name);
-        // println!(
-        //     "\tDevice Name: {}, id: {}, type: {}",
-        //     device_name, device_properties.device_id, device_type
-        // );
-
-        // let major_version = vk_version_major!(device_properties.api_version);
-        // let minor_version = vk_version_minor!(device_properties.api_version);
-        // let patch_version = vk_version_patch!(device_properties.api_version);
-
-        // println!(
-        //     "\tAPI Version: {}.{}.{}",
-        //     major_version, minor_version, patch_version
-        // );
-
-        // println!("\tSupport Queue Family: {}", device_queue_families.len());
-        // println!("\t\tQueue Count | Graphics, Compute, Transfer, Sparse Binding");
-        // for queue_family in device_queue_families.iter() {
-        //     let is_graphics_support = if queue_family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-        //     {
-        //         "support"
-        //     } else {
-        //         "unsupport"
-        //     };
-        //     let is_compute_support = if queue_family.queue_flags.contains(vk::QueueFlags::COMPUTE) {
-        //         "support"
-        //     } else {
-        //         "unsupport"
-        //     };
-        //     let is_transfer_support = if queue_family.queue_flags.contains(vk::QueueFlags::TRANSFER)
-        //     {
-        //         "support"
-        //     } else {
-        //         "unsupport"
-        //     };
-        //     let is_sparse_support = if queue_family
-        //         .queue_flags
-        //         .contains(vk::QueueFlags::SPARSE_BINDING)
-        //     {
-        //         "support"
-        //     } else {
-        //         "unsupport"
-        //     };
-
-        //     println!(
-        //         "\t\t{}\t    | {},  {},  {},  {}",
-        //         queue_family.queue_count,
-        //         is_graphics_support,
-        //         is_compute_support,
-        //         is_transfer_support,
-        //         is_sparse_support
-        //     );
-        // }
-
-        // // there are plenty of features
-        // println!(
-        //     "\tGeometry Shader support: {}",
-        //     if device_features.geometry_shader == 1 {
-        //         "Support"
-        //     } else {
-        //         "Unsupport"
-        //     }
-        // );
-
-        let indices = PhysicalDevice::find_queue_family(instance, physical_device, surface);
-
-        let is_queue_family_supported = indices.is_complete();
-        let is_device_extension_supported = PhysicalDevice::check_device_extension_support(instance, physical_device);
-
-        let is_swapchain_supported = 
-            if is_device_extension_supported {
-                let swapchain_support = Swapchain::query_swapchain_support(physical_device, surface);
-                !swapchain_support.formats.is_empty() && !swapchain_support.present_modes.is_empty()
+            if reasons.is_empty() {
+                candidates.push((PhysicalDevice::device_rank(&instance.raw, physical_device), index, device_name, physical_device));
+            } else {
+                rejected.push(format!("\t[{}] {} ({:?}): {}", index, device_name, properties.device_type, reasons.join(", ")));
             }
-            else {
-                println!("Current device doesn't support swapchain!");
-                false
-            };
+        }
 
-        let is_support_sampler_anisotropy = device_features.sampler_anisotropy == 1;
+        // stable sort, so equally ranked devices keep the driver's order
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.0));
 
-        return is_queue_family_supported 
-            && is_device_extension_supported 
-            && is_swapchain_supported
-            && is_support_sampler_anisotropy;
+        match candidates.first() {
+            Some((_, index, device_name, physical_device)) => {
+                log::info!("Using GPU [{}]: {}", index, device_name);
+                Ok(*physical_device)
+            }
+            None => Err(BackendError::NoSuitableDevice { reasons: rejected }.into()),
+        }
     }
 
-    pub fn find_queue_family(
-        instance: &ash::Instance,
+    // empty when the device can be used
+    fn unsuitable_reasons(
+        instance: &Instance,
         physical_device: vk::PhysicalDevice,
-        surface: &Surface,
-    ) -> QueueFamilyIndices {
-        let queue_families =
-            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        surface: Option<&Surface>,
+        required_device_extensions: &debug::DeviceExtension,
+        features: &DeviceFeatureRequest,
+    ) -> Vec<String> {
+        let properties = unsafe { instance.raw.get_physical_device_properties(physical_device) };
+        let extensions = PhysicalDevice::query_extensions(&instance.raw, physical_device);
+        let device_features = DeviceFeatures::query(instance, physical_device, &properties, &extensions);
 
-        let mut queue_family_indices = QueueFamilyIndices::new();
+        let mut reasons = vec![];
 
-        let mut index = 0;
-        for queue_family in queue_families.iter() {
-            if queue_family.queue_count > 0
-                && queue_family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-            {
-                queue_family_indices.graphics_family = Some(index);
-            }
+        let indices = PhysicalDevice::find_queue_family(&instance.raw, physical_device, surface);
 
-            let is_present_support = unsafe {
-                surface
-                    .fns
-                    .get_physical_device_surface_support(
-                        physical_device,
-                        index as u32,
-                        surface.raw,
-                    )
-            };
+        if indices.graphics_family.is_none() {
+            reasons.push("no graphics queue".to_owned());
+        }
 
-            if queue_family.queue_count > 0 && is_present_support.unwrap() {
-                queue_family_indices.present_family = Some(index);
+        let missing_extensions = required_device_extensions.missing_required(&extensions);
+        if !missing_extensions.is_empty() {
+            reasons.push(format!("missing device extensions {}", missing_extensions.join(", ")));
+        }
+
+        // swapchain requirements only matter when presenting
+        if let Some(surface) = surface {
+            if indices.present_family.is_none() {
+                reasons.push("no present support for the surface".to_owned());
             }
 
-            if queue_family_indices.is_complete() {
-                break;
+            // querying the surface needs VK_KHR_swapchain to make sense
+            if missing_extensions.is_empty() {
+                match Swapchain::query_swapchain_support(physical_device, surface) {
+                    std::result::Result::Ok(support) if support.formats.is_empty() || support.present_modes.is_empty() => {
+                        reasons.push("no surface formats or present modes".to_owned());
+                    }
+                    std::result::Result::Ok(_) => {}
+                    Err(err) => reasons.push(format!("failed to query the surface: {}", err)),
+                }
             }
+        }
 
-            index += 1;
+        for feature in features.missing_required(&device_features) {
+            reasons.push(format!("missing feature {:?}", feature));
         }
 
-        //to do
-    //     queue_families.into_iter()
-    //     .map()
-    //     .filter(|queue_index, queue_family| {
-    //         if queue_family.queue_count > 0
-    //         && queue_family.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
-    //             Ok(queue_index)
-    //         }
-    //         else {
-    //             None
-    //         };
-    //     }
-    // );
-        
-        queue_family_indices
+        reasons
     }
 
-    fn check_device_extension_support(
+    pub fn find_queue_family(
         instance: &ash::Instance,
         physical_device: vk::PhysicalDevice,
-    ) -> bool {
-        let available_extensions = unsafe {
-            instance
-                .enumerate_device_extension_properties(physical_device)
-                .expect("Failed to get device extension properties.")
+        surface: Option<&Surface>,
+    ) -> QueueFamilyIndices {
+        let queue_families =
+            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+
+        let mut queue_family_indices = QueueFamilyIndices::new();
+
+        let present_support = |index: u32| {
+            surface.is_some_and(|surface| unsafe {
+                surface
+                    .fns
+                    .get_physical_device_surface_support(physical_device, index, surface.raw)
+                    .unwrap_or(false)
+            })
         };
 
-        let mut available_extension_names = vec![];
+        let usable = queue_families
+            .iter()
+            .enumerate()
+            .filter(|(_, queue_family)| queue_family.queue_count > 0)
+            .map(|(index, queue_family)| (index as u32, queue_family.queue_flags))
+            .collect::<Vec<_>>();
+
+        let find = |pred: &dyn Fn(vk::QueueFlags) -> bool| {
+            usable
+                .iter()
+                .find(|(_, flags)| pred(*flags))
+                .map(|(index, _)| *index)
+        };
 
-        println!("\tAvailable Device Extensions: ");
-        for extension in available_extensions.iter() {
-            let extension_name = tools::vk_to_string(&extension.extension_name);
-            println!(
-                "\t\tName: {}, Version: {}",
-                extension_name, extension.spec_version
-            );
+        queue_family_indices.graphics_family = find(&|flags| flags.contains(vk::QueueFlags::GRAPHICS));
 
-            available_extension_names.push(extension_name);
+        // presenting from the graphics family avoids sharing swapchain images between queues
+        if surface.is_some() {
+            queue_family_indices.present_family = queue_family_indices
+                .graphics_family
+                .filter(|&index| present_support(index))
+                .or_else(|| usable.iter().map(|(index, _)| *index).find(|&index| present_support(index)));
         }
 
-        let mut required_extensions = HashSet::new();
-        for extension in constants::DEVICE_EXTENSIONS.names.iter() {
-            required_extensions.insert(extension.to_string());
-        }
+        queue_family_indices.compute_family = find(&|flags| {
+            flags.contains(vk::QueueFlags::COMPUTE) && !flags.contains(vk::QueueFlags::GRAPHICS)
+        })
+        .or(queue_family_indices.graphics_family);
 
-        for extension_name in available_extension_names.iter() {
-            required_extensions.remove(extension_name);
-        }
+        // graphics and compute queues can always transfer, so only the flag-only family is dedicated
+        queue_family_indices.transfer_family = find(&|flags| {
+            flags.contains(vk::QueueFlags::TRANSFER)
+                && !flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        })
+        .or(queue_family_indices.graphics_family);
 
-        return required_extensions.is_empty();
+        queue_family_indices
     }
 
 }
@@ -331,4 +637,71 @@ impl PhysicalDevice {
 //             }
 //         })
 //         .collect()
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_properties(heaps: &[(vk::DeviceSize, vk::MemoryHeapFlags)]) -> vk::PhysicalDeviceMemoryProperties {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_heap_count: heaps.len() as u32,
+            ..Default::default()
+        };
+        for (heap, &(size, flags)) in memory_properties.memory_heaps.iter_mut().zip(heaps) {
+            *heap = vk::MemoryHeap { size, flags };
+        }
+        memory_properties
+    }
+
+    #[test]
+    fn selection_from_config() {
+        assert_eq!(DeviceSelection::from_config(None, None), DeviceSelection::Best);
+        assert_eq!(DeviceSelection::from_config(Some(1), None), DeviceSelection::Index(1));
+        assert_eq!(
+            DeviceSelection::from_config(Some(1), Some("nvidia")),
+            DeviceSelection::Name("nvidia".to_owned())
+        );
+    }
+
+    #[test]
+    fn selection_env_overrides_config() {
+        let selection = DeviceSelection::Index(0);
+        assert_eq!(selection.clone().or_value(None), DeviceSelection::Index(0));
+        assert_eq!(selection.clone().or_value(Some("  ")), DeviceSelection::Index(0));
+        assert_eq!(selection.clone().or_value(Some(" 2 ")), DeviceSelection::Index(2));
+        assert_eq!(selection.or_value(Some("llvmpipe")), DeviceSelection::Name("llvmpipe".to_owned()));
+    }
+
+    #[test]
+    fn selection_rejects() {
+        assert_eq!(DeviceSelection::Best.rejects(3, "any"), None);
+        assert_eq!(DeviceSelection::Index(1).rejects(1, "any"), None);
+        assert!(DeviceSelection::Index(1).rejects(0, "any").is_some());
+
+        let selection = DeviceSelection::Name("GeForce".to_owned());
+        assert_eq!(selection.rejects(0, "NVIDIA GEFORCE RTX 3080"), None);
+        assert!(selection.rejects(0, "llvmpipe (LLVM 15.0.7, 256 bits)").is_some());
+    }
+
+    #[test]
+    fn rank_prefers_device_type_then_local_memory() {
+        let local = vk::MemoryHeapFlags::DEVICE_LOCAL;
+        let small = memory_properties(&[(1 << 30, local)]);
+        // host heaps don't count
+        let large = memory_properties(&[(8 << 30, local), (64 << 30, vk::MemoryHeapFlags::empty())]);
+
+        let discrete = PhysicalDevice::rank(vk::PhysicalDeviceType::DISCRETE_GPU, &small);
+        let integrated = PhysicalDevice::rank(vk::PhysicalDeviceType::INTEGRATED_GPU, &large);
+        let cpu = PhysicalDevice::rank(vk::PhysicalDeviceType::CPU, &large);
+        let other = PhysicalDevice::rank(vk::PhysicalDeviceType::OTHER, &large);
+
+        assert!(discrete > integrated);
+        assert!(integrated > cpu);
+        assert!(cpu > other);
+        assert_eq!(integrated.1, 8 << 30);
+        assert!(
+            PhysicalDevice::rank(vk::PhysicalDeviceType::DISCRETE_GPU, &large) > discrete
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::Result;
+use ash::extensions::khr;
+use ash::vk;
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::vulkan::timeline::GpuFuture;
+
+// a device queue; Vulkan wants submissions to one queue externally synchronized, so everything
+// goes through `last_submitted`'s lock. `Device` hands out one `Queue` per handle, aliased queue
+// types share it
+pub struct Queue {
+    pub(crate) raw: vk::Queue,
+    pub(crate) family_index: u32,
+    device: ash::Device,
+    // every submission gets the next value
+    last_submitted: Mutex<u64>,
+    // signalled with each submission's value, `None` without timeline semaphores
+    pub(crate) timeline: Option<vk::Semaphore>,
+    // without a timeline: the highest value the host saw complete through a fence or an idle wait
+    completed: AtomicU64,
+}
+
+impl Queue {
+    // the timeline semaphore is destroyed by `Device`
+    pub(crate) fn new(device: &ash::Device, raw: vk::Queue, family_index: u32, timeline: bool) -> Result<Queue> {
+        let timeline = if timeline {
+            let mut type_info = vk::SemaphoreTypeCreateInfo::builder()
+                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                .initial_value(0);
+            let create_info = vk::SemaphoreCreateInfo::builder().push_next(&mut type_info);
+
+            Some(unsafe { device.create_semaphore(&create_info, None)? })
+        } else {
+            None
+        };
+
+        Ok(Queue {
+            raw,
+            family_index,
+            device: device.clone(),
+            last_submitted: Mutex::new(0),
+            timeline,
+            completed: AtomicU64::new(0),
+        })
+    }
+
+    pub fn raw(&self) -> vk::Queue {
+        self.raw
+    }
+
+    pub fn family_index(&self) -> u32 {
+        self.family_index
+    }
+
+    // returns the submission's value, see `completed`
+    pub fn submit(&self, batch: &SubmitBatch) -> Result<u64> {
+        let mut last_submitted = self.last_submitted.lock().unwrap();
+        let value = *last_submitted + 1;
+
+        let signalled;
+        let batch = match self.timeline {
+            Some(timeline) => {
+                signalled = batch.clone().signal_timeline(timeline, value);
+                &signalled
+            }
+            None => batch,
+        };
+
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+            .wait_semaphore_values(&batch.wait_values)
+            .signal_semaphore_values(&batch.signal_values);
+        let mut submit_info = vk::SubmitInfo::builder()
+            .command_buffers(&batch.command_buffers)
+            .wait_semaphores(&batch.wait_semaphores)
+            .wait_dst_stage_mask(&batch.wait_stages)
+            .signal_semaphores(&batch.signal_semaphores);
+        // binary-only submissions mustn't need timeline support
+        if batch.has_timeline_values {
+            submit_info = submit_info.push_next(&mut timeline_info);
+        }
+
+        unsafe {
+            self.device
+                .queue_submit(self.raw, &[submit_info.build()], batch.fence)?
+        };
+        *last_submitted = value;
+
+        Ok(value)
+    }
+
+    pub fn last_submitted(&self) -> u64 {
+        *self.last_submitted.lock().unwrap()
+    }
+
+    // every submission up to this value is done on the GPU
+    pub fn completed(&self) -> Result<u64> {
+        match self.timeline {
+            Some(timeline) => Ok(unsafe { self.device.get_semaphore_counter_value(timeline)? }),
+            None => Ok(self.completed.load(Ordering::Acquire)),
+        }
+    }
+
+    // for host waits on fences, which the queue can't see by itself
+    pub(crate) fn mark_completed(&self, value: u64) {
+        self.completed.fetch_max(value, Ordering::AcqRel);
+    }
+
+    // `Ok(true)` when the swapchain is suboptimal
+    pub fn present(
+        &self,
+        swapchain_fns: &khr::Swapchain,
+        present_info: &vk::PresentInfoKHR,
+    ) -> ash::prelude::VkResult<bool> {
+        let _lock = self.last_submitted.lock().unwrap();
+        unsafe { swapchain_fns.queue_present(self.raw, present_info) }
+    }
+
+    pub fn wait_idle(&self) -> Result<()> {
+        let last_submitted = self.last_submitted.lock().unwrap();
+        unsafe { self.device.queue_wait_idle(self.raw)? };
+        self.mark_completed(*last_submitted);
+
+        Ok(())
+    }
+}
+
+// what one `vkQueueSubmit` batch waits on, runs and signals
+#[derive(Clone, Default)]
+pub struct SubmitBatch {
+    command_buffers: Vec<vk::CommandBuffer>,
+    wait_semaphores: Vec<vk::Semaphore>,
+    wait_stages: Vec<vk::PipelineStageFlags>,
+    // 0 for binary semaphores, which ignore it
+    wait_values: Vec<u64>,
+    signal_semaphores: Vec<vk::Semaphore>,
+    signal_values: Vec<u64>,
+    has_timeline_values: bool,
+    fence: vk::Fence,
+}
+
+impl SubmitBatch {
+    pub fn new(command_buffers: &[vk::CommandBuffer]) -> Self {
+        SubmitBatch {
+            command_buffers: command_buffers.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    pub fn command_buffer(mut self, command_buffer: vk::CommandBuffer) -> Self {
+        self.command_buffers.push(command_buffer);
+        self
+    }
+
+    // `stage` is where the batch's commands start waiting for the binary `semaphore`
+    pub fn wait(mut self, semaphore: vk::Semaphore, stage: vk::PipelineStageFlags) -> Self {
+        self.wait_semaphores.push(semaphore);
+        self.wait_stages.push(stage);
+        self.wait_values.push(0);
+        self
+    }
+
+    pub fn wait_timeline(mut self, semaphore: vk::Semaphore, value: u64, stage: vk::PipelineStageFlags) -> Self {
+        self.wait_semaphores.push(semaphore);
+        self.wait_stages.push(stage);
+        self.wait_values.push(value);
+        self.has_timeline_values = true;
+        self
+    }
+
+    pub fn wait_future(self, future: GpuFuture, stage: vk::PipelineStageFlags) -> Self {
+        self.wait_timeline(future.semaphore, future.value, stage)
+    }
+
+    pub fn signal(mut self, semaphore: vk::Semaphore) -> Self {
+        self.signal_semaphores.push(semaphore);
+        self.signal_values.push(0);
+        self
+    }
+
+    pub fn signal_timeline(mut self, semaphore: vk::Semaphore, value: u64) -> Self {
+        self.signal_semaphores.push(semaphore);
+        self.signal_values.push(value);
+        self.has_timeline_values = true;
+        self
+    }
+
+    // signalled once the batch's command buffers are done, has to be unsignalled when submitted
+    pub fn fence(mut self, fence: vk::Fence) -> Self {
+        self.fence = fence;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn semaphore(raw: u64) -> vk::Semaphore {
+        vk::Semaphore::from_raw(raw)
+    }
+
+    #[test]
+    fn waits_stay_aligned() {
+        let batch = SubmitBatch::new(&[])
+            .wait(semaphore(1), vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .wait_timeline(semaphore(2), 7, vk::PipelineStageFlags::TRANSFER)
+            .wait_future(
+                GpuFuture {
+                    semaphore: semaphore(3),
+                    value: 9,
+                },
+                vk::PipelineStageFlags::VERTEX_INPUT,
+            );
+
+        assert_eq!(batch.wait_semaphores, vec![semaphore(1), semaphore(2), semaphore(3)]);
+        assert_eq!(
+            batch.wait_stages,
+            vec![
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+            ]
+        );
+        // binary waits carry a 0 so the values line up with the semaphores
+        assert_eq!(batch.wait_values, vec![0, 7, 9]);
+    }
+
+    #[test]
+    fn signals_stay_aligned() {
+        let batch = SubmitBatch::new(&[])
+            .signal_timeline(semaphore(1), 4)
+            .signal(semaphore(2));
+
+        assert_eq!(batch.signal_semaphores, vec![semaphore(1), semaphore(2)]);
+        assert_eq!(batch.signal_values, vec![4, 0]);
+    }
+
+    #[test]
+    fn only_timeline_semaphores_need_values() {
+        let binary = SubmitBatch::new(&[])
+            .wait(semaphore(1), vk::PipelineStageFlags::ALL_COMMANDS)
+            .signal(semaphore(2));
+        assert!(!binary.has_timeline_values);
+
+        let waits = binary.clone().wait_timeline(semaphore(3), 1, vk::PipelineStageFlags::ALL_COMMANDS);
+        assert!(waits.has_timeline_values);
+
+        let signals = binary.signal_timeline(semaphore(3), 1);
+        assert!(signals.has_timeline_values);
+    }
+
+    #[test]
+    fn command_buffers_and_fence() {
+        let first = vk::CommandBuffer::from_raw(1);
+        let second = vk::CommandBuffer::from_raw(2);
+
+        let batch = SubmitBatch::new(&[first]).command_buffer(second);
+        assert_eq!(batch.command_buffers, vec![first, second]);
+        assert_eq!(batch.fence, vk::Fence::null());
+
+        let fence = vk::Fence::from_raw(3);
+        assert_eq!(batch.fence(fence).fence, fence);
+    }
+}
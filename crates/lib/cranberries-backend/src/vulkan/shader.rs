@@ -2,19 +2,17 @@ use anyhow::Ok;
 use anyhow::Result;
 use ash::vk;
 use ash::vk::ImageView;
-use winapi::um::winuser::VK_NUMLOCK;
 
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::ptr;
-use std::ffi::CString;
 
 use crate::vulkan::logical_device::Device;
 use crate::vulkan::image::ImageDesc;
 use crate::vulkan::buffer::Vertex;
+use crate::vulkan::error::BackendError;
 
-use super::surface;
 
 
 
@@ -92,6 +90,12 @@ pub struct RasterPipelineDesc {
     pub push_constants_bytes: usize,
 }
 
+impl Default for RasterPipelineDesc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RasterPipelineDesc {
     pub fn new() -> RasterPipelineDesc {
         RasterPipelineDesc {
@@ -109,6 +113,7 @@ pub struct RenderPassAttachmentDesc {
     pub load_op: vk::AttachmentLoadOp,
     pub store_op: vk::AttachmentStoreOp,
     pub samples: vk::SampleCountFlags,
+    pub final_layout: vk::ImageLayout,
 }
 
 impl RenderPassAttachmentDesc {
@@ -118,6 +123,7 @@ impl RenderPassAttachmentDesc {
             load_op: vk::AttachmentLoadOp::CLEAR,
             store_op: vk::AttachmentStoreOp::STORE,
             samples: vk::SampleCountFlags::TYPE_1,
+            final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
         }
     }
 
@@ -127,6 +133,7 @@ impl RenderPassAttachmentDesc {
             load_op: vk::AttachmentLoadOp::CLEAR,
             store_op: vk::AttachmentStoreOp::DONT_CARE,
             samples: vk::SampleCountFlags::TYPE_1,
+            final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
         }
     }
 
@@ -145,6 +152,13 @@ impl RenderPassAttachmentDesc {
         self
     }
 
+    // offscreen (headless) targets can't end up in PRESENT_SRC_KHR
+    pub fn with_final_layout(mut self, final_layout: vk::ImageLayout) -> Self {
+        self.final_layout = final_layout;
+        self
+    }
+
+    #[allow(dead_code)]
     fn to_vk(
         self,
         initial_layout: vk::ImageLayout,
@@ -177,7 +191,7 @@ impl FramebufferCacheKey {
         depth_stencil_attachment: Option<&'a ImageDesc>,
     ) -> Self {
         let color_attachments : Vec<(vk::ImageUsageFlags, vk::ImageCreateFlags)> = color_attachments
-            .chain(depth_stencil_attachment.into_iter())
+            .chain(depth_stencil_attachment)
             .copied()
             .map(|attachment| (attachment.usage, attachment.flags))
             .collect();
@@ -208,6 +222,7 @@ impl FramebufferCacheKey {
 
 }
 
+#[allow(dead_code)]
 pub struct FramebufferCache {
     entries: Mutex<HashMap<FramebufferCacheKey, vk::Framebuffer>>,
     color_attachment_descs: Vec<RenderPassAttachmentDesc>,//[RenderPassAttachmentDesc; MAX_COLOR_ATTACHMENTS],
@@ -218,6 +233,18 @@ pub struct FramebufferCache {
     //entry: vk::Framebuffer,
 }
 
+// a framebuffer from `FramebufferCache`, what `CommandRecorder::begin_render_pass` renders into
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Framebuffer {
+    pub(crate) raw: vk::Framebuffer,
+}
+
+impl Framebuffer {
+    pub fn raw(&self) -> vk::Framebuffer {
+        self.raw
+    }
+}
+
 pub struct RenderPassDesc<'a> {
     pub color_attachments: &'a [RenderPassAttachmentDesc],
     pub depth_attachment: Option<RenderPassAttachmentDesc>,
@@ -235,13 +262,33 @@ impl RenderPass {
         device: &Arc<Device>, 
         desc: RenderPassDesc<'_>,
         //format: vk::Format,
+        name: Option<&str>,
     ) -> Result<RenderPass> {
-        
+        for attachment in desc.color_attachments {
+            if !device.pdevice.supports_format(attachment.format, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::COLOR_ATTACHMENT) {
+                anyhow::bail!(BackendError::UnsupportedFormat {
+                    format: attachment.format,
+                    features: vk::FormatFeatureFlags::COLOR_ATTACHMENT,
+                });
+            }
+        }
+        // the framebuffer cache always binds a depth view
+        let depth_attachment = desc.depth_attachment.ok_or_else(|| {
+            BackendError::UnsupportedFeature("render passes without a depth attachment".to_owned())
+        })?;
+        if let Some(attachment) = desc.depth_attachment.as_ref() {
+            if !device.pdevice.supports_format(attachment.format, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT) {
+                anyhow::bail!(BackendError::UnsupportedFormat {
+                    format: attachment.format,
+                    features: vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+                });
+            }
+        }
+
         let render_pass_attachments = desc
             .color_attachments
             .iter()
-            .enumerate()
-            .map(|(idx, renderpass_attachment_desc)| {
+            .map(|renderpass_attachment_desc| {
                 vk::AttachmentDescription {
                     format: renderpass_attachment_desc.format,//desc.color_attachments[idx].format,
                     flags: vk::AttachmentDescriptionFlags::empty(),
@@ -250,7 +297,7 @@ impl RenderPass {
                     store_op: renderpass_attachment_desc.store_op,
                     stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
                     initial_layout: vk::ImageLayout::UNDEFINED,//COLOR_ATTACHMENT_OPTIMAL,UNDEFINED
-                    final_layout: vk::ImageLayout::PRESENT_SRC_KHR,//COLOR_ATTACHMENT_OPTIMAL,
+                    final_layout: renderpass_attachment_desc.final_layout,//COLOR_ATTACHMENT_OPTIMAL,
                     ..Default::default()
                 }
             })
@@ -263,7 +310,7 @@ impl RenderPass {
                     store_op: renderpass_attachment_desc.store_op,
                     stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
                     initial_layout: vk::ImageLayout::UNDEFINED,
-                    final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    final_layout: renderpass_attachment_desc.final_layout,
                     ..Default::default()
                 }
             }))
@@ -372,10 +419,13 @@ impl RenderPass {
         let render_pass = unsafe {
             device
                 .raw
-                .create_render_pass(&renderpass_create_info, None)
-                .expect("Failed to create render pass!")
+                .create_render_pass(&renderpass_create_info, None)?
         };
 
+        if let Some(name) = name {
+            device.set_debug_name(render_pass, name);
+        }
+
         // Arc::new(RenderPass {
         //     device: device.clone(),
         //     raw: render_pass,
@@ -392,7 +442,7 @@ impl RenderPass {
             framebuffer_cache: FramebufferCache::new(
                 //render_pass, 
                 desc.color_attachments, 
-                desc.depth_attachment.unwrap(),
+                depth_attachment,
             ),
         })
     }
@@ -475,8 +525,8 @@ impl FramebufferCache {
         image_view: ImageView,
         depth_image_view: ImageView,
         render_pass: vk::RenderPass,
-    ) -> anyhow::Result<vk::Framebuffer> {
-        let mut entries = self.entries.lock().unwrap();
+    ) -> anyhow::Result<Framebuffer> {
+        let _entries = self.entries.lock().unwrap();
 
         /* 
         if let Some((entry)) = entries.get(&key) {
@@ -554,15 +604,11 @@ impl FramebufferCache {
                     layers: 1,
                 };
     
-                unsafe {
-                    device
-                        .create_framebuffer(&framebuffer_create_info, None)
-                        .expect("Failed to create Framebuffer!")
-                }
+                unsafe { device.create_framebuffer(&framebuffer_create_info, None)? }
             };
 
             //entries.insert(key, entry);
-            Ok(entry)
+            Ok(Framebuffer { raw: entry })
         }
     
     //}
@@ -575,7 +621,8 @@ pub fn create_graphics_pipline(
     //vert_shader_module: vk::ShaderModule,
     //frag_shader_module: vk::ShaderModule,
     ubo_set_layout: vk::DescriptorSetLayout,
-) -> (vk::Pipeline, vk::PipelineLayout) {
+    name: Option<&str>,
+) -> Result<RasterPipeline> {
     // let vert_shader_module = create_shader_module(
     //     device,
     //     include_bytes!("../../../../shaders/spv/09-shader-base.vert.spv").to_vec(),
@@ -588,13 +635,18 @@ pub fn create_graphics_pipline(
     let vert_shader_module = create_shader_module(
         device,
         include_bytes!("../../../../shaders/spv/26-shader-depth.vert.spv").to_vec(),
-    );
+    )?;
     let frag_shader_module = create_shader_module(
         device,
         include_bytes!("../../../../shaders/spv/26-shader-depth.frag.spv").to_vec(),
-    );
+    )
+    .inspect_err(|_| unsafe { device.raw.destroy_shader_module(vert_shader_module, None) })?;
+    let destroy_shader_modules = || unsafe {
+        device.raw.destroy_shader_module(vert_shader_module, None);
+        device.raw.destroy_shader_module(frag_shader_module, None);
+    };
 
-    let main_function_name = CString::new("main").unwrap(); // the beginning function name in shader code.
+    let main_function_name = c"main"; // the beginning function name in shader code.
 
     let shader_stages = [
         vk::PipelineShaderStageCreateInfo {
@@ -755,7 +807,7 @@ pub fn create_graphics_pipline(
         device
             .raw
             .create_pipeline_layout(&pipeline_layout_create_info, None)
-            .expect("Failed to create pipeline layout!")
+            .inspect_err(|_| destroy_shader_modules())?
     };
 
     let graphic_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo {
@@ -788,40 +840,55 @@ pub fn create_graphics_pipline(
                 &graphic_pipeline_create_infos,
                 None,
             )
-            .expect("Failed to create Graphics Pipeline!.")
+            .map_err(|(_, err)| err)
     };
-
-    unsafe {
-        device.raw.destroy_shader_module(vert_shader_module, None);
-        device.raw.destroy_shader_module(frag_shader_module, None);
+    destroy_shader_modules();
+    let graphics_pipelines = graphics_pipelines.inspect_err(|_| unsafe {
+        device.raw.destroy_pipeline_layout(pipeline_layout, None);
+    })?;
+
+    if let Some(name) = name {
+        device.set_debug_name(graphics_pipelines[0], name);
+        device.set_debug_name(pipeline_layout, &format!("{} layout", name));
     }
 
-    (graphics_pipelines[0], pipeline_layout)
+    Ok(RasterPipeline {
+        common: ShaderPipelineCommon {
+            pipeline_layout,
+            pipeline: graphics_pipelines[0],
+            set_layout_info: Vec::new(),
+            descriptor_pool_sizes: Vec::new(),
+            descriptor_set_layouts: vec![ubo_set_layout],
+            pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+        },
+    })
 }
 
-pub fn create_shader_module(device: &Arc<Device>, code: Vec<u8>) -> vk::ShaderModule {
+pub fn create_shader_module(device: &Arc<Device>, code: Vec<u8>) -> Result<vk::ShaderModule> {
+    // also copies the words into u32-aligned storage, which the byte vector doesn't guarantee
+    let code = ash::util::read_spv(&mut std::io::Cursor::new(&code))
+        .map_err(|err| BackendError::InvalidSpirv(err.to_string()))?;
+    if code.first() != Some(&0x0723_0203) {
+        return Err(BackendError::InvalidSpirv("missing the SPIR-V magic number".to_owned()).into());
+    }
+
     let shader_module_create_info = vk::ShaderModuleCreateInfo {
         s_type: vk::StructureType::SHADER_MODULE_CREATE_INFO,
         p_next: ptr::null(),
         flags: vk::ShaderModuleCreateFlags::empty(),
-        code_size: code.len(),
-        p_code: code.as_ptr() as *const u32,
+        code_size: code.len() * 4,
+        p_code: code.as_ptr(),
     };
 
-    unsafe {
-        device
-            .raw
-            .create_shader_module(&shader_module_create_info, None)
-            .expect("Failed to create Shader Module!")
-    }
+    Ok(unsafe { device.raw.create_shader_module(&shader_module_create_info, None)? })
 }
 
 pub fn create_framebuffers(
     device: &Arc<Device>,
     render_pass: vk::RenderPass,
-    image_views: &Vec<vk::ImageView>,
+    image_views: &[vk::ImageView],
     swapchain_extent: vk::Extent2D,
-) -> Vec<vk::Framebuffer> {
+) -> Result<Vec<vk::Framebuffer>> {
     let mut framebuffers = vec![];
 
     for &image_view in image_views.iter() {
@@ -839,15 +906,10 @@ pub fn create_framebuffers(
             layers: 1,
         };
 
-        let framebuffer = unsafe {
-            device
-                .raw
-                .create_framebuffer(&framebuffer_create_info, None)
-                .expect("Failed to create Framebuffer!")
-        };
+        let framebuffer = unsafe { device.raw.create_framebuffer(&framebuffer_create_info, None)? };
 
         framebuffers.push(framebuffer);
     }
 
-    framebuffers
+    Ok(framebuffers)
 }
\ No newline at end of file
@@ -1,8 +1,7 @@
 use anyhow::Result;
 use ash::{extensions::khr, vk};
-use std::sync::Arc;
 
-use crate::{vulkan::instance::Instance, platforms};
+use crate::vulkan::instance::Instance;
 
 pub struct Surface {
     pub(crate) raw: vk::SurfaceKHR,
@@ -25,6 +24,17 @@ impl Surface {
             fns: surface_loader,
         })
     }
+
+    pub fn supports_queue_family(
+        &self,
+        physical_device: vk::PhysicalDevice,
+        queue_family_index: u32,
+    ) -> Result<bool> {
+        Ok(unsafe {
+            self.fns
+                .get_physical_device_surface_support(physical_device, queue_family_index, self.raw)?
+        })
+    }
 }
 
 impl Drop for Surface {
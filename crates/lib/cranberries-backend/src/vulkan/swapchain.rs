@@ -1,18 +1,15 @@
 use anyhow::Ok;
-use anyhow::bail;
 use ash::vk;
 use ash::extensions::khr;
 use ash::vk::DeviceMemory;
-use ash::vk::Queue;
 
-use crate::vulkan::logical_device::Device;
+use crate::vulkan::logical_device::{Device, DeviceFrame};
 use crate::vulkan::surface::Surface;
 use crate::vulkan::instance::Instance;
 use crate::vulkan::physical_device::PhysicalDevice;
 use crate::vulkan::physical_device::QueueFamilyIndices;
-use crate::constants;
 use crate::vulkan::image::*;
-use crate::vulkan::swapchain;
+use crate::vulkan::error::BackendError;
 
 use std::sync::Arc;
 use anyhow::Result;
@@ -36,11 +33,8 @@ pub struct Swapchain {
     //pub swapchain_format: vk::Format,
     //pub swapchain_dims: vk::Extent2D,
     pub images: Vec<Arc<Image>>,
-    pub acquire_semaphores: Vec<vk::Semaphore>,
-
-    // TODO: move out of swapchain, make a single semaphore
-    pub rendering_finished_semaphores: Vec<vk::Semaphore>,
-    pub next_semaphore: usize,
+    // one per image: signalled by `Device::finish_frame`, waited on when presenting that image
+    rendering_complete_semaphores: Vec<vk::Semaphore>,
 
     // Keep a reference in order not to drop after the device
     #[allow(dead_code)]
@@ -79,10 +73,15 @@ impl Swapchain {
         queue_family: &QueueFamilyIndices,
         swapchain_desc: SwapchainDesc,
     ) -> Result<Swapchain> {
-        let swapchain_support = Swapchain::query_swapchain_support(physical_device.raw, surface);
+        let present_family = match (queue_family.present_family, device.present_queue()) {
+            (Some(present_family), Some(_)) => present_family,
+            _ => anyhow::bail!(BackendError::UnsupportedFeature("a swapchain without a present queue".to_owned())),
+        };
+
+        let swapchain_support = Swapchain::query_swapchain_support(physical_device.raw, surface)?;
         
-        let surface_format = Swapchain::choose_swapchain_format(&swapchain_support.formats);
-        let present_mode = Swapchain::choose_swapchain_present_mode(&swapchain_support.present_modes);
+        let surface_format = Swapchain::choose_swapchain_format(&swapchain_support.formats)?;
+        let present_mode = Swapchain::choose_swapchain_present_mode(&swapchain_support.present_modes, swapchain_desc.vsync);
         let extent = Swapchain::choose_swapchain_extent(&swapchain_support.capabilities, swapchain_desc.dims);
 
         let image_count = swapchain_support.capabilities.min_image_count + 1;
@@ -93,13 +92,13 @@ impl Swapchain {
         };
 
         let (image_sharing_mode, queue_family_index_count, queue_family_indices) =
-            if queue_family.graphics_family != queue_family.present_family {
+            if queue_family.graphics_family != Some(present_family) {
                 (
                     vk::SharingMode::CONCURRENT,
                     2,
                     vec![
                         queue_family.graphics_family.unwrap(),
-                        queue_family.present_family.unwrap(),
+                        present_family,
                     ],
                 )
             } else {
@@ -127,21 +126,21 @@ impl Swapchain {
             image_array_layers: 1,
         };
 
-        println!("{:#?}", swapchain_create_info);
-
         let fns = ash::extensions::khr::Swapchain::new(&instance.raw, &device.raw);
         let raw = unsafe {
             fns
-                .create_swapchain(&swapchain_create_info, None)
-                .expect("Failed to create Swapchain!")
+                .create_swapchain(&swapchain_create_info, None)?
         };
 
         let vk_images = unsafe {
             fns
-                .get_swapchain_images(raw)
-                .expect("Failed to get Swapchain Images.")
+                .get_swapchain_images(raw)?
         };
         
+        for (i, vk_image) in vk_images.iter().enumerate() {
+            device.set_debug_name(*vk_image, &format!("swapchain image {}", i));
+        }
+
         let images: Vec<Arc<Image>> = vk_images
         .into_iter()
         .map(|vk_image| {
@@ -159,12 +158,20 @@ impl Swapchain {
                 },
                 views: Default::default(),
                 device: device.raw.clone(),
-                device_memory: DeviceMemory::default(),
+                // no memory of ours: the swapchain owns the image, only views are released
+                device_memory: DeviceMemory::null(),
+                release_queue: device.release_queue.clone(),
             })
         })
         .collect();
-    
-        assert_eq!(image_count, images.len() as u32);
+
+        let rendering_complete_semaphores = (0..images.len())
+            .map(|i| {
+                let semaphore = unsafe { device.raw.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)? };
+                device.set_debug_name(semaphore, &format!("swapchain image {} rendering complete semaphore", i));
+                Ok(semaphore)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         // let swapchain_images = images
         // .into_iter()
@@ -190,28 +197,6 @@ impl Swapchain {
         // })
         // .collect();
 
-        let acquire_semaphores = (0..images.len())
-        .map(|_| {
-            unsafe {
-                device
-                    .raw
-                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
-            }
-            .unwrap()
-        })
-        .collect();
-
-    let rendering_finished_semaphores = (0..images.len())
-        .map(|_| {
-            unsafe {
-                device
-                    .raw
-                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
-            }
-            .unwrap()
-        })
-        .collect();
-
         Ok(Swapchain {
             fns,
             raw,
@@ -223,63 +208,67 @@ impl Swapchain {
             desc: SwapchainDesc {
                 format: surface_format,
                 dims: extent,
-                vsync: false,
+                vsync: swapchain_desc.vsync,
             },
-            acquire_semaphores,
-            rendering_finished_semaphores,
-            next_semaphore: 0,
             images,
+            rendering_complete_semaphores,
         })
     }
 
     pub fn query_swapchain_support(
         physical_device: vk::PhysicalDevice,
         surface: &Surface,
-    ) -> SwapChainSupportDetail {
+    ) -> Result<SwapChainSupportDetail> {
         unsafe {
             let capabilities = surface
                 .fns
-                .get_physical_device_surface_capabilities(physical_device, surface.raw)
-                .expect("Failed to query for surface capabilities.");
+                .get_physical_device_surface_capabilities(physical_device, surface.raw)?;
             let formats = surface
                 .fns
-                .get_physical_device_surface_formats(physical_device, surface.raw)
-                .expect("Failed to query for surface formats.");
+                .get_physical_device_surface_formats(physical_device, surface.raw)?;
             let present_modes = surface
                 .fns
-                .get_physical_device_surface_present_modes(physical_device, surface.raw)
-                .expect("Failed to query for surface present mode.");
+                .get_physical_device_surface_present_modes(physical_device, surface.raw)?;
 
-            SwapChainSupportDetail {
+            Ok(SwapChainSupportDetail {
                 capabilities,
                 formats,
                 present_modes,
-            }
+            })
         }
     }
 
     fn choose_swapchain_format(
-        available_formats: &Vec<vk::SurfaceFormatKHR>
-    ) -> vk::SurfaceFormatKHR {
+        available_formats: &[vk::SurfaceFormatKHR]
+    ) -> Result<vk::SurfaceFormatKHR> {
         // check if list contains most widely used R8G8B8A8 format with nonlinear color space
         for available_format in available_formats {
             if available_format.format == vk::Format::B8G8R8A8_SRGB
                 && available_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
             {
-                return available_format.clone();
+                return Ok(*available_format);
             }
         }
 
         // return the first format from the list
-        return available_formats.first().unwrap().clone();
+        match available_formats.first() {
+            Some(format) => Ok(*format),
+            None => Err(BackendError::UnsupportedFeature("surface reports no formats".to_owned()).into()),
+        }
     }
 
     fn choose_swapchain_present_mode(
-        available_present_modes: &Vec<vk::PresentModeKHR>,
+        available_present_modes: &[vk::PresentModeKHR],
+        vsync: bool,
     ) -> vk::PresentModeKHR {
-        for &available_present_mode in available_present_modes.iter() {
-            if available_present_mode == vk::PresentModeKHR::MAILBOX {
-                return available_present_mode;
+        // FIFO is the only mode guaranteed to be there, and the only one that waits for vblank
+        if vsync {
+            return vk::PresentModeKHR::FIFO;
+        }
+
+        for &preferred_present_mode in [vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE].iter() {
+            if available_present_modes.contains(&preferred_present_mode) {
+                return preferred_present_mode;
             }
         }
 
@@ -287,7 +276,7 @@ impl Swapchain {
     }
 
     fn choose_swapchain_extent(capabilities: &vk::SurfaceCapabilitiesKHR, desired_extent: vk::Extent2D) -> vk::Extent2D {
-        if capabilities.current_extent.width != u32::max_value() {
+        if capabilities.current_extent.width != u32::MAX {
             capabilities.current_extent
         } else {
             use num::clamp;
@@ -309,13 +298,15 @@ impl Swapchain {
         }
     }
 
-    pub fn acquire_next_image(&mut self) -> std::result::Result<SwapchainImage, vk::Result> {
-        let acquire_semaphore = self.acquire_semaphores[self.next_semaphore];
-        let rendering_finished_semaphore = self.rendering_finished_semaphores[self.next_semaphore];
+    // signals the frame's semaphore and hands it the image's, so `Device::finish_frame` knows
+    // to wait for the image; fails with `BackendError::OutOfDate` when the swapchain has to be
+    // recreated
+    pub fn acquire_next_image(&mut self, frame: &DeviceFrame) -> Result<SwapchainImage> {
+        let acquire_semaphore = frame.swapchain_acquired_semaphore;
 
         let present_index = unsafe {
             self.fns.acquire_next_image(self.raw, 
-                std::u64::MAX, 
+                u64::MAX, 
                 acquire_semaphore, 
                 vk::Fence::null(),
             )
@@ -324,53 +315,41 @@ impl Swapchain {
 
         match present_index {
             std::result::Result::Ok(present_index) => {
-                assert_eq!(present_index, self.next_semaphore);
+                let rendering_finished_semaphore = self.rendering_complete_semaphores[present_index];
+                *frame.present_semaphore.lock().unwrap() = Some(rendering_finished_semaphore);
 
-                self.next_semaphore = (self.next_semaphore + 1) % self.images.len();
-                
-                std::result::Result::Ok(SwapchainImage {
+                Ok(SwapchainImage {
                     image: self.images[present_index].clone(),
                     image_index: present_index as u32,
                     acquire_semaphore,
                     rendering_finished_semaphore,
                 })
             }
-            Err(err)
-                if err == vk::Result::ERROR_OUT_OF_DATE_KHR
-                    || err == vk::Result::SUBOPTIMAL_KHR =>
-            {
-                // bail!("Could not recreate framebuffer")
-                std::result::Result::Err(err)
-            }
-            err => {
-                panic!("Could not acquire swapchain image: {:?}", err);
-            }
+            Err(err) => Err(self.device.report_device_lost(BackendError::from(err))),
         }
     }
 
-    pub fn present_image(&self, image: SwapchainImage) {
+    // an out of date swapchain isn't an error here, the next acquire reports it
+    pub fn present_image(&self, image: SwapchainImage) -> Result<()> {
 
         let present_info = vk::PresentInfoKHR::builder()
             .wait_semaphores(std::slice::from_ref(&image.rendering_finished_semaphore))
             .swapchains(std::slice::from_ref(&self.raw))
             .image_indices(std::slice::from_ref(&image.image_index));
 
-        unsafe {
-            match self
-                .fns
-                .queue_present(self.device._present_queue, &present_info)
-            {
-                std::result::Result::Ok(_) => (),
-                Err(err)
-                    if err == vk::Result::ERROR_OUT_OF_DATE_KHR
-                        || err == vk::Result::SUBOPTIMAL_KHR =>
-                {
-                    // Handled in the next frame
-                }
-                err => {
-                    panic!("Could not present image: {:?}", err);
-                }
+        // a swapchain is only ever created for a device with a present queue
+        let present_queue = match self.device.present_queue() {
+            Some(present_queue) => present_queue,
+            None => anyhow::bail!(BackendError::UnsupportedFeature("presenting from a headless device".to_owned())),
+        };
+
+        match present_queue.present(&self.fns, &present_info) {
+            std::result::Result::Ok(_) => Ok(()),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                // Handled in the next frame
+                Ok(())
             }
+            Err(err) => Err(self.device.report_device_lost(BackendError::from(err))),
         }
     }
 }
@@ -378,16 +357,12 @@ impl Swapchain {
 impl Drop for Swapchain {
     fn drop(&mut self) {
         unsafe {
-            for acquire_semaphore in self.acquire_semaphores.iter_mut() {
-                self.device
-                .raw
-                .destroy_semaphore(*acquire_semaphore, None);
+            // the last presents may still be waiting on the semaphores
+            if let Some(present_queue) = self.device.present_queue() {
+                let _ = present_queue.wait_idle();
             }
-
-            for rendering_finished_semaphore in self.rendering_finished_semaphores.iter_mut() {
-                self.device
-                .raw
-                .destroy_semaphore(*rendering_finished_semaphore, None);
+            for semaphore in self.rendering_complete_semaphores.drain(..) {
+                self.device.raw.destroy_semaphore(semaphore, None);
             }
 
             self.fns
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use ash::vk;
+
+use std::sync::Arc;
+
+use crate::vulkan::error::BackendError;
+use crate::vulkan::features::DeviceFeature;
+use crate::vulkan::logical_device::Device;
+use crate::vulkan::queue::{Queue, SubmitBatch};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QueueType {
+    Graphics,
+    Compute,
+    Transfer,
+}
+
+impl QueueType {
+    pub const ALL: [QueueType; 3] = [QueueType::Graphics, QueueType::Compute, QueueType::Transfer];
+}
+
+// the timeline semaphore of one queue; every submission to the queue signals the next value
+pub struct QueueTimeline {
+    pub(crate) queue: Arc<Queue>,
+    pub(crate) semaphore: vk::Semaphore,
+}
+
+impl QueueTimeline {
+    // `None` when the queue was created without a timeline
+    pub(crate) fn new(queue: Arc<Queue>) -> Option<QueueTimeline> {
+        let semaphore = queue.timeline?;
+        Some(QueueTimeline { queue, semaphore })
+    }
+
+    pub fn semaphore(&self) -> vk::Semaphore {
+        self.semaphore
+    }
+
+    pub fn last_submitted(&self) -> u64 {
+        self.queue.last_submitted()
+    }
+
+    // a future for everything submitted so far
+    pub fn current(&self) -> GpuFuture {
+        GpuFuture {
+            semaphore: self.semaphore,
+            value: self.last_submitted(),
+        }
+    }
+
+    // the value the GPU has reached
+    pub fn completed(&self, device: &Device) -> Result<u64> {
+        Ok(unsafe { device.raw.get_semaphore_counter_value(self.semaphore)? })
+    }
+}
+
+// completes once the GPU has reached `value` on a queue timeline; plain data, so it can be
+// copied across threads and passed as a wait to submissions on other queues
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpuFuture {
+    pub(crate) semaphore: vk::Semaphore,
+    pub value: u64,
+}
+
+impl GpuFuture {
+    pub fn is_complete(&self, device: &Device) -> Result<bool> {
+        let reached = unsafe { device.raw.get_semaphore_counter_value(self.semaphore)? };
+        Ok(reached >= self.value)
+    }
+
+    // `false` when `timeout_ns` ran out first
+    pub fn wait(&self, device: &Device, timeout_ns: u64) -> Result<bool> {
+        GpuFuture::wait_all(device, std::slice::from_ref(self), timeout_ns)
+    }
+
+    pub fn wait_all(device: &Device, futures: &[GpuFuture], timeout_ns: u64) -> Result<bool> {
+        if futures.is_empty() {
+            return Ok(true);
+        }
+
+        let semaphores = futures.iter().map(|future| future.semaphore).collect::<Vec<_>>();
+        let values = futures.iter().map(|future| future.value).collect::<Vec<_>>();
+        let wait_info = vk::SemaphoreWaitInfo::builder()
+            .semaphores(&semaphores)
+            .values(&values);
+
+        match unsafe { device.raw.wait_semaphores(&wait_info, timeout_ns) } {
+            Err(vk::Result::TIMEOUT) => Ok(false),
+            result => {
+                result.map_err(|err| device.report_device_lost(err))?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl Device {
+    // the family `queue` was created from, graphics when it has no dedicated family
+    pub fn queue_family_index(&self, queue: QueueType) -> u32 {
+        let indices = self.queue_family_indices();
+        let graphics_family = indices.graphics_family.unwrap();
+        match queue {
+            QueueType::Graphics => graphics_family,
+            QueueType::Compute => indices.compute_family.unwrap_or(graphics_family),
+            QueueType::Transfer => indices.transfer_family.unwrap_or(graphics_family),
+        }
+    }
+
+    pub fn timelines_enabled(&self) -> bool {
+        !self.timelines.is_empty()
+    }
+
+    // compute and transfer share the graphics timeline when they alias its queue
+    pub fn timeline(&self, queue: QueueType) -> Result<&QueueTimeline> {
+        match self.timelines.get(queue as usize) {
+            Some(timeline) => Ok(timeline.as_ref()),
+            None => anyhow::bail!(BackendError::UnsupportedFeature(format!(
+                "timeline submission needs {:?}, which isn't enabled on this device",
+                DeviceFeature::TimelineSemaphore
+            ))),
+        }
+    }
+
+    // submits `command_buffers` on `queue` once every future in `wait` has completed, and
+    // returns the future for this submission
+    pub fn submit(
+        &self,
+        queue: QueueType,
+        command_buffers: &[vk::CommandBuffer],
+        wait: &[GpuFuture],
+    ) -> Result<GpuFuture> {
+        let timeline = self.timeline(queue)?;
+
+        let batch = wait
+            .iter()
+            .fold(SubmitBatch::new(command_buffers), |batch, future| {
+                batch.wait_future(*future, vk::PipelineStageFlags::ALL_COMMANDS)
+            });
+
+        // the queue signals its timeline with the value
+        let value = timeline
+            .queue
+            .submit(&batch)
+            .map_err(|err| self.report_device_lost(err))?;
+
+        Ok(GpuFuture {
+            semaphore: timeline.semaphore,
+            value,
+        })
+    }
+}
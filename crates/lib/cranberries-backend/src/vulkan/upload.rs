@@ -0,0 +1,541 @@
+use anyhow::Result;
+use ash::vk;
+
+use std::path::Path;
+use std::ptr;
+
+use crate::vulkan::buffer::{Buffer, BufferDesc, Vertex};
+use crate::vulkan::image::{self, Image, ImageDesc, ImageType};
+use crate::vulkan::logical_device::{CommandBuffer, Device};
+use crate::vulkan::queue::SubmitBatch;
+use crate::vulkan::error::BackendError;
+use crate::vulkan::timeline::{GpuFuture, QueueType};
+
+// records any number of buffer and image uploads into one command buffer and submits them
+// together; on a dedicated transfer or compute family the resources are handed over to the
+// graphics family before the returned `Upload` completes
+pub struct UploadBatch<'a> {
+    device: &'a Device,
+    queue: QueueType,
+    command_buffer: CommandBuffer,
+    // queue family ownership acquires, recorded on the graphics queue at submit
+    acquire_buffer_barriers: Vec<vk::BufferMemoryBarrier>,
+    acquire_image_barriers: Vec<vk::ImageMemoryBarrier>,
+    staging_buffers: Vec<Buffer>,
+}
+
+// completes once the GPU has consumed its batch; owns the staging memory until then
+pub struct Upload<'a> {
+    device: &'a Device,
+    completion: UploadCompletion,
+    command_buffers: Vec<CommandBuffer>,
+    staging_buffers: Vec<Buffer>,
+}
+
+enum UploadCompletion {
+    Timeline(GpuFuture),
+    // without timeline semaphores: the batch's command buffer fence and graphics queue value
+    Fence(vk::Fence, u64),
+}
+
+impl Device {
+    // `queue` is a preference: without timeline semaphores there is no way to hand resources
+    // between queues, so the batch goes to the graphics queue
+    pub fn begin_upload(&self, queue: QueueType) -> Result<UploadBatch<'_>> {
+        let queue = if self.timelines_enabled() {
+            queue
+        } else {
+            QueueType::Graphics
+        };
+
+        let command_buffer = CommandBuffer::create(&self.raw, self.queue_family_index(queue))?;
+        self.set_debug_name(command_buffer.raw, &format!("{:?} upload command buffer", queue).to_lowercase());
+
+        unsafe {
+            self.raw.begin_command_buffer(
+                command_buffer.raw,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+        }
+
+        Ok(UploadBatch {
+            device: self,
+            queue,
+            command_buffer,
+            acquire_buffer_barriers: Vec::new(),
+            acquire_image_barriers: Vec::new(),
+            staging_buffers: Vec::new(),
+        })
+    }
+}
+
+impl<'a> UploadBatch<'a> {
+    pub fn queue(&self) -> QueueType {
+        self.queue
+    }
+
+    fn needs_ownership_transfer(&self) -> bool {
+        self.device.queue_family_index(self.queue) != self.device.queue_family_index(QueueType::Graphics)
+    }
+
+    fn create_staging_buffer(&mut self, data: &[u8], name: &str) -> Result<vk::Buffer> {
+        let staging_buffer_desc = BufferDesc {
+            size: data.len(),
+            usage: vk::BufferUsageFlags::TRANSFER_SRC,
+            required_memory_flags: vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            alignment: None,
+        };
+
+        let staging_buffer = self.device.create_buffer(staging_buffer_desc, Some(name), None)?;
+
+        unsafe {
+            let data_ptr = self.device.raw.map_memory(
+                staging_buffer.device_memory,
+                0,
+                data.len() as u64,
+                vk::MemoryMapFlags::empty(),
+            )? as *mut u8;
+
+            data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+
+            self.device.raw.unmap_memory(staging_buffer.device_memory);
+        }
+
+        let raw = staging_buffer.raw;
+        self.staging_buffers.push(staging_buffer);
+        Ok(raw)
+    }
+
+    // `dst` needs TRANSFER_DST usage
+    pub fn upload_buffer(&mut self, dst: &Buffer, dst_offset: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let overruns = dst_offset
+            .checked_add(data.len() as u64)
+            .is_none_or(|end| end > dst.desc.size as u64);
+        if overruns {
+            anyhow::bail!(BackendError::InvalidArgument(format!(
+                "upload of {} bytes at offset {} overruns a {} byte buffer",
+                data.len(),
+                dst_offset,
+                dst.desc.size
+            )));
+        }
+
+        let staging_buffer = self.create_staging_buffer(data, "upload staging buffer")?;
+        self.record_buffer_copy(staging_buffer, dst, vk::BufferCopy {
+            src_offset: 0,
+            dst_offset,
+            size: data.len() as u64,
+        });
+
+        Ok(())
+    }
+
+    // a buffer-to-buffer copy in the same batch, e.g. out of a staging buffer the caller filled
+    pub fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer) {
+        self.record_buffer_copy(src.raw, dst, vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: src.desc.size.min(dst.desc.size) as u64,
+        });
+    }
+
+    fn record_buffer_copy(&mut self, src: vk::Buffer, dst: &Buffer, region: vk::BufferCopy) {
+        unsafe {
+            self.device
+                .raw
+                .cmd_copy_buffer(self.command_buffer.raw, src, dst.raw, &[region]);
+        }
+
+        let upload_family = self.device.queue_family_index(self.queue);
+        let graphics_family = self.device.queue_family_index(QueueType::Graphics);
+        let barrier = vk::BufferMemoryBarrier {
+            src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+            dst_access_mask: vk::AccessFlags::empty(),
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            buffer: dst.raw,
+            offset: region.dst_offset,
+            size: region.size,
+            ..Default::default()
+        };
+
+        // on another family this is the release half of the ownership transfer, otherwise the
+        // copy is made visible to whatever reads the buffer on the graphics queue next
+        let (barrier, dst_stage) = if self.needs_ownership_transfer() {
+            let release = vk::BufferMemoryBarrier {
+                src_queue_family_index: upload_family,
+                dst_queue_family_index: graphics_family,
+                ..barrier
+            };
+            self.acquire_buffer_barriers.push(vk::BufferMemoryBarrier {
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::MEMORY_READ,
+                ..release
+            });
+            (release, vk::PipelineStageFlags::BOTTOM_OF_PIPE)
+        } else {
+            let visible = vk::BufferMemoryBarrier {
+                dst_access_mask: vk::AccessFlags::VERTEX_ATTRIBUTE_READ
+                    | vk::AccessFlags::INDEX_READ
+                    | vk::AccessFlags::UNIFORM_READ
+                    | vk::AccessFlags::SHADER_READ,
+                ..barrier
+            };
+            let stages = vk::PipelineStageFlags::VERTEX_INPUT
+                | vk::PipelineStageFlags::VERTEX_SHADER
+                | vk::PipelineStageFlags::FRAGMENT_SHADER;
+            (visible, stages)
+        };
+
+        unsafe {
+            self.device.raw.cmd_pipeline_barrier(
+                self.command_buffer.raw,
+                vk::PipelineStageFlags::TRANSFER,
+                dst_stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+    }
+
+    // creates `desc` with TRANSFER_DST added and fills it with `data`
+    pub fn create_buffer_with_data(
+        &mut self,
+        mut desc: BufferDesc,
+        name: Option<&str>,
+        data: &[u8],
+    ) -> Result<Buffer> {
+        desc.usage |= vk::BufferUsageFlags::TRANSFER_DST;
+
+        let buffer = self.device.create_buffer(desc, name, None)?;
+        self.upload_buffer(&buffer, 0, data)?;
+
+        Ok(buffer)
+    }
+
+    pub fn create_vertex_buffer(&mut self, vertex_data: &[Vertex]) -> Result<Buffer> {
+        let vertex_buffer_desc = BufferDesc {
+            size: std::mem::size_of_val(vertex_data),
+            usage: vk::BufferUsageFlags::VERTEX_BUFFER,
+            required_memory_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            alignment: None,
+        };
+
+        // `Vertex` is `#[repr(C)]` and all f32s, no padding
+        self.create_buffer_with_data(vertex_buffer_desc, Some("vertex buffer"), unsafe { as_bytes(vertex_data) })
+    }
+
+    pub fn create_index_buffer(&mut self, index_data: &[u32]) -> Result<Buffer> {
+        let index_buffer_desc = BufferDesc {
+            size: std::mem::size_of_val(index_data),
+            usage: vk::BufferUsageFlags::INDEX_BUFFER,
+            required_memory_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            alignment: None,
+        };
+
+        // plain u32s, no padding
+        self.create_buffer_with_data(index_buffer_desc, Some("index buffer"), unsafe { as_bytes(index_data) })
+    }
+
+    // fills mip 0, layer 0 of a 2D color image with tightly packed texels; the image is left in
+    // SHADER_READ_ONLY_OPTIMAL once the upload completes
+    pub fn upload_image(&mut self, image: &Image, data: &[u8]) -> Result<()> {
+        let desc = &image.desc;
+        if desc.image_type != ImageType::Tex2d {
+            anyhow::bail!(BackendError::InvalidArgument(format!(
+                "upload_image only fills 2D images, got {:?}",
+                desc.image_type
+            )));
+        }
+        if !desc.usage.contains(vk::ImageUsageFlags::TRANSFER_DST) {
+            anyhow::bail!(BackendError::InvalidArgument(format!(
+                "upload_image needs an image with TRANSFER_DST usage, got {:?}",
+                desc.usage
+            )));
+        }
+        let texel_size = match image::color_texel_size(desc.format) {
+            Some(texel_size) => texel_size,
+            None => anyhow::bail!(BackendError::UnsupportedFormat {
+                format: desc.format,
+                features: vk::FormatFeatureFlags::TRANSFER_DST,
+            }),
+        };
+        let expected_len = desc.extent[0] as u64 * desc.extent[1] as u64 * texel_size;
+        if data.len() as u64 != expected_len {
+            anyhow::bail!(BackendError::InvalidArgument(format!(
+                "upload_image got {} bytes for a {}x{} {:?} image, expected {}",
+                data.len(),
+                desc.extent[0],
+                desc.extent[1],
+                desc.format,
+                expected_len
+            )));
+        }
+
+        let staging_buffer = self.create_staging_buffer(data, "texture staging buffer")?;
+
+        let upload_family = self.device.queue_family_index(self.queue);
+        let graphics_family = self.device.queue_family_index(QueueType::Graphics);
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+
+        let to_transfer_dst = vk::ImageMemoryBarrier {
+            s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+            p_next: ptr::null(),
+            src_access_mask: vk::AccessFlags::empty(),
+            dst_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+            old_layout: vk::ImageLayout::UNDEFINED,
+            new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            image: image.raw,
+            subresource_range,
+        };
+
+        let buffer_image_regions = [vk::BufferImageCopy {
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_extent: vk::Extent3D {
+                width: image.desc.extent[0],
+                height: image.desc.extent[1],
+                depth: 1,
+            },
+            buffer_offset: 0,
+            buffer_image_height: 0,
+            buffer_row_length: 0,
+            image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+        }];
+
+        // on another family this is the release half of the ownership transfer
+        let to_shader_read = if upload_family == graphics_family {
+            vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ..to_transfer_dst
+            }
+        } else {
+            vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::empty(),
+                old_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                src_queue_family_index: upload_family,
+                dst_queue_family_index: graphics_family,
+                ..to_transfer_dst
+            }
+        };
+        let after_copy_stage = if upload_family == graphics_family {
+            vk::PipelineStageFlags::FRAGMENT_SHADER
+        } else {
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE
+        };
+
+        unsafe {
+            let raw = &self.device.raw;
+            let cb = self.command_buffer.raw;
+
+            raw.cmd_pipeline_barrier(
+                cb,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_transfer_dst],
+            );
+            raw.cmd_copy_buffer_to_image(
+                cb,
+                staging_buffer,
+                image.raw,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &buffer_image_regions,
+            );
+            raw.cmd_pipeline_barrier(
+                cb,
+                vk::PipelineStageFlags::TRANSFER,
+                after_copy_stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_shader_read],
+            );
+        }
+
+        if upload_family != graphics_family {
+            self.acquire_image_barriers.push(vk::ImageMemoryBarrier {
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                ..to_shader_read
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn create_texture_image(&mut self, image_path: &Path) -> Result<Image> {
+        let (image_width, image_height, image_data) = image::load_rgba8(image_path)?;
+
+        let texture_image_desc = ImageDesc {
+            image_type: ImageType::Tex2d,
+            usage: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            flags: vk::ImageCreateFlags::empty(),
+            format: vk::Format::R8G8B8A8_SRGB,
+            extent: [image_width, image_height, 1],
+            tiling: vk::ImageTiling::OPTIMAL,
+            mip_levels: 1,
+            array_elements: 1,
+        };
+
+        let texture_image = self.device.create_image(texture_image_desc, vk::MemoryPropertyFlags::DEVICE_LOCAL, image_path.to_str())?;
+        self.upload_image(&texture_image, &image_data)?;
+
+        Ok(texture_image)
+    }
+
+    // one submission for everything recorded, plus a small one on the graphics queue when
+    // ownership has to be acquired there
+    pub fn submit(self) -> Result<Upload<'a>> {
+        let UploadBatch {
+            device,
+            queue,
+            command_buffer,
+            acquire_buffer_barriers,
+            acquire_image_barriers,
+            staging_buffers,
+        } = self;
+
+        unsafe {
+            device.raw.end_command_buffer(command_buffer.raw)?;
+        }
+
+        let mut command_buffers = vec![command_buffer];
+
+        let completion = if device.timelines_enabled() {
+            let mut future = device.submit(queue, &[command_buffers[0].raw], &[])?;
+
+            if !acquire_buffer_barriers.is_empty() || !acquire_image_barriers.is_empty() {
+                let acquire_cb = CommandBuffer::create(&device.raw, device.queue_family_index(QueueType::Graphics))?;
+                device.set_debug_name(acquire_cb.raw, "upload acquire command buffer");
+
+                unsafe {
+                    device.raw.begin_command_buffer(
+                        acquire_cb.raw,
+                        &vk::CommandBufferBeginInfo::builder()
+                            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+                    )?;
+                    device.raw.cmd_pipeline_barrier(
+                        acquire_cb.raw,
+                        vk::PipelineStageFlags::TOP_OF_PIPE,
+                        vk::PipelineStageFlags::ALL_COMMANDS,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &acquire_buffer_barriers,
+                        &acquire_image_barriers,
+                    );
+                    device.raw.end_command_buffer(acquire_cb.raw)?;
+                }
+
+                future = device.submit(QueueType::Graphics, &[acquire_cb.raw], &[future])?;
+                command_buffers.push(acquire_cb);
+            }
+
+            UploadCompletion::Timeline(future)
+        } else {
+            let fence = command_buffers[0].submit_done_fence;
+
+            unsafe { device.raw.reset_fences(&[fence])? };
+            let value = device
+                .graphics_queue()
+                .submit(&SubmitBatch::new(std::slice::from_ref(&command_buffers[0].raw)).fence(fence))
+                .map_err(|err| device.report_device_lost(err))?;
+
+            UploadCompletion::Fence(fence, value)
+        };
+
+        Ok(Upload {
+            device,
+            completion,
+            command_buffers,
+            staging_buffers,
+        })
+    }
+}
+
+impl Upload<'_> {
+    pub fn is_ready(&self) -> Result<bool> {
+        let ready = match self.completion {
+            UploadCompletion::Timeline(future) => future.is_complete(self.device)?,
+            UploadCompletion::Fence(fence, _) => unsafe { self.device.raw.get_fence_status(fence)? },
+        };
+        if ready {
+            self.completed()?;
+        }
+        Ok(ready)
+    }
+
+    pub fn wait(&self) -> Result<()> {
+        match self.completion {
+            UploadCompletion::Timeline(future) => {
+                future.wait(self.device, u64::MAX)?;
+            }
+            UploadCompletion::Fence(fence, _) => unsafe {
+                self.device.raw.wait_for_fences(&[fence], true, u64::MAX)?;
+            },
+        }
+        self.completed()
+    }
+
+    // resources dropped while the upload was in flight may be free now
+    fn completed(&self) -> Result<()> {
+        if let UploadCompletion::Fence(_, value) = self.completion {
+            self.device.graphics_queue().mark_completed(value);
+        }
+        self.device.collect_garbage()
+    }
+
+    // for making other submissions wait on the upload instead of the host; `None` without
+    // timeline semaphores
+    pub fn future(&self) -> Option<GpuFuture> {
+        match self.completion {
+            UploadCompletion::Timeline(future) => Some(future),
+            UploadCompletion::Fence(..) => None,
+        }
+    }
+}
+
+impl Drop for Upload<'_> {
+    // blocks until the GPU is done with the staging buffers and command buffers
+    fn drop(&mut self) {
+        let _ = self.wait();
+
+        for staging_buffer in self.staging_buffers.drain(..) {
+            self.device.immediate_destroy_buffer(staging_buffer);
+        }
+        self.command_buffers.clear();
+    }
+}
+
+// plain-old-data view for uploads
+//
+// Safety: `T` has to be free of padding, uninitialized bytes can't be read as `u8`
+pub(crate) unsafe fn as_bytes<T: Copy>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
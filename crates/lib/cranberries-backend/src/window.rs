@@ -28,6 +28,12 @@ pub struct ProgramProc {
     pub event_loop: EventLoop<()>,
 }
 
+impl Default for ProgramProc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProgramProc {
 
     pub fn new() -> ProgramProc {